@@ -0,0 +1,173 @@
+//! Admin HTTP API under `/admin`. Every route requires `Authorization: Bearer <ADMIN_TOKEN>`,
+//! and the API is disabled when no token is configured.
+
+use std::time::Duration;
+use actix_web::{dev::Payload, error, http::header, web, FromRequest, HttpRequest, HttpResponse, };
+use futures_util::future::{ready, Ready};
+use serde::Deserialize;
+use crate::bans::BanTarget;
+use crate::config::ChatServerConfig;
+use crate::moderation::ReportStatus;
+use crate::server::{constant_time_eq, ChatServerError, ChatServerHandle};
+
+/// Largest page of results returned by list endpoints
+const MAX_PAGE_SIZE: usize = 200;
+
+/// Extractor that only succeeds for requests carrying the admin token
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req))
+    }
+}
+
+fn authorize(req: &HttpRequest) -> Result<Admin, actix_web::Error> {
+    let expected = req
+        .app_data::<web::Data<ChatServerConfig>>()
+        .and_then(|config| config.admin_token.clone())
+        .ok_or_else(|| error::ErrorServiceUnavailable("admin API is disabled; set ADMIN_TOKEN"))?;
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(Admin),
+        _ => Err(error::ErrorUnauthorized("invalid admin token")),
+    }
+}
+
+#[derive(Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl Page {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(50).min(MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportFilter {
+    status: Option<ReportStatus>,
+}
+
+async fn list_reports(
+    _: Admin,
+    srv: web::Data<ChatServerHandle>,
+    filter: web::Query<ReportFilter>,
+    page: web::Query<Page>,
+) -> Result<HttpResponse, ChatServerError> {
+    let reports = srv.list_reports(filter.status, page.offset, page.limit()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "reports": reports })))
+}
+
+async fn get_report(_: Admin, srv: web::Data<ChatServerHandle>, id: web::Path<u64>) -> Result<HttpResponse, ChatServerError> {
+    Ok(match srv.get_report(id.into_inner()).await? {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("no such report"),
+    })
+}
+
+#[derive(Deserialize)]
+struct Resolution {
+    status: ReportStatus,
+    note: Option<String>,
+}
+
+async fn resolve_report(
+    _: Admin,
+    srv: web::Data<ChatServerHandle>,
+    id: web::Path<u64>,
+    resolution: web::Json<Resolution>,
+) -> Result<HttpResponse, ChatServerError> {
+    let Resolution { status, note } = resolution.into_inner();
+    Ok(match srv.resolve_report(id.into_inner(), status, note).await? {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("no such report"),
+    })
+}
+
+async fn list_bans(_: Admin, srv: web::Data<ChatServerHandle>) -> Result<HttpResponse, ChatServerError> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "bans": srv.list_bans().await? })))
+}
+
+#[derive(Deserialize)]
+struct NewBan {
+    #[serde(flatten)]
+    target: BanTarget,
+    /// Omit for a permanent ban
+    duration_secs: Option<u64>,
+    reason: Option<String>,
+}
+
+async fn add_ban(_: Admin, srv: web::Data<ChatServerHandle>, ban: web::Json<NewBan>) -> Result<HttpResponse, ChatServerError> {
+    let NewBan { target, duration_secs, reason } = ban.into_inner();
+    let ban = srv.add_ban(target, duration_secs.map(Duration::from_secs), reason).await?;
+    Ok(HttpResponse::Created().json(ban))
+}
+
+async fn remove_ban(_: Admin, srv: web::Data<ChatServerHandle>, id: web::Path<u64>) -> Result<HttpResponse, ChatServerError> {
+    Ok(match srv.remove_ban(id.into_inner()).await? {
+        Some(ban) => HttpResponse::Ok().json(ban),
+        None => HttpResponse::NotFound().body("no such ban"),
+    })
+}
+
+async fn list_sessions(_: Admin, srv: web::Data<ChatServerHandle>, page: web::Query<Page>) -> Result<HttpResponse, ChatServerError> {
+    let sessions = srv.list_sessions(page.offset, page.limit()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "sessions": sessions })))
+}
+
+async fn list_waiting(_: Admin, srv: web::Data<ChatServerHandle>) -> Result<HttpResponse, ChatServerError> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "buckets": srv.list_waiting().await? })))
+}
+
+async fn list_pairs(_: Admin, srv: web::Data<ChatServerHandle>, page: web::Query<Page>) -> Result<HttpResponse, ChatServerError> {
+    let pairs = srv.list_pairs(page.offset, page.limit()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "pairs": pairs })))
+}
+
+async fn list_groups(_: Admin, srv: web::Data<ChatServerHandle>, page: web::Query<Page>) -> Result<HttpResponse, ChatServerError> {
+    let groups = srv.list_groups(page.offset, page.limit()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "groups": groups })))
+}
+
+async fn kick(_: Admin, srv: web::Data<ChatServerHandle>, conn: web::Path<String>) -> Result<HttpResponse, ChatServerError> {
+    Ok(if srv.kick(conn.into_inner()).await? {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body("no such session")
+    })
+}
+
+async fn dissolve_group(_: Admin, srv: web::Data<ChatServerHandle>, code: web::Path<String>) -> Result<HttpResponse, ChatServerError> {
+    Ok(if srv.dissolve_group(code.into_inner()).await? {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().body("no such group")
+    })
+}
+
+/// Register the admin routes; mounted under `/admin`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/reports", web::get().to(list_reports))
+        .route("/reports/{id}", web::get().to(get_report))
+        .route("/reports/{id}/resolve", web::post().to(resolve_report))
+        .route("/bans", web::get().to(list_bans))
+        .route("/bans", web::post().to(add_ban))
+        .route("/bans/{id}", web::delete().to(remove_ban))
+        .route("/sessions", web::get().to(list_sessions))
+        .route("/sessions/{conn_id}/kick", web::post().to(kick))
+        .route("/waiting", web::get().to(list_waiting))
+        .route("/pairs", web::get().to(list_pairs))
+        .route("/groups", web::get().to(list_groups))
+        .route("/groups/{code}/dissolve", web::post().to(dissolve_group));
+}
@@ -0,0 +1,98 @@
+//! Bans on user ids, IP addresses and device fingerprints, managed through the admin API.
+
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+use serde::{Deserialize, Serialize};
+use crate::moderation::unix_now;
+use crate::protocol::ServerMessage;
+use crate::server::duration_ms;
+
+/// What a ban applies to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum BanTarget {
+    UserId(String),
+    Ip(IpAddr),
+    Device(String),
+}
+
+#[derive(Serialize, Clone)]
+pub struct Ban {
+    pub id: u64,
+    pub target: BanTarget,
+    pub reason: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    /// Unix timestamp in seconds, or `None` for a permanent ban
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    /// Time left on the ban, or `None` if it is permanent
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at.map(|expires_at| Duration::from_secs(expires_at.saturating_sub(unix_now())))
+    }
+
+    /// The `banned` event telling a client about this ban
+    pub fn event(&self) -> ServerMessage {
+        ServerMessage::Banned {
+            reason: self.reason.clone(),
+            remaining_ms: self.remaining().map(duration_ms),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the ban covers a client with the given identity
+    pub fn matches(&self, user_id: Option<&str>, ip: Option<IpAddr>, device: Option<&str>) -> bool {
+        match &self.target {
+            BanTarget::UserId(banned) => user_id == Some(banned.as_str()),
+            BanTarget::Ip(banned) => ip == Some(*banned),
+            BanTarget::Device(banned) => device == Some(banned.as_str()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BanList {
+    bans: BTreeMap<u64, Ban>,
+    next_id: u64,
+}
+
+impl BanList {
+    pub fn add(&mut self, target: BanTarget, duration: Option<Duration>, reason: Option<String>) -> Ban {
+        self.next_id += 1;
+        let now = unix_now();
+        let ban = Ban {
+            id: self.next_id,
+            target,
+            reason,
+            created_at: now,
+            expires_at: duration.map(|duration| now.saturating_add(duration.as_secs())),
+        };
+        self.bans.insert(ban.id, ban.clone());
+        ban
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Ban> {
+        self.bans.remove(&id)
+    }
+
+    /// Active bans, oldest first
+    pub fn list(&mut self) -> Vec<Ban> {
+        self.prune();
+        self.bans.values().cloned().collect()
+    }
+
+    /// The active ban covering a client, if any
+    pub fn find(&mut self, user_id: Option<&str>, ip: Option<IpAddr>, device: Option<&str>) -> Option<Ban> {
+        self.prune();
+        self.bans.values().find(|ban| ban.matches(user_id, ip, device)).cloned()
+    }
+
+    fn prune(&mut self) {
+        let now = unix_now();
+        self.bans.retain(|_, ban| !ban.is_expired(now));
+    }
+}
@@ -0,0 +1,183 @@
+use std::{env, str::FromStr, time::Duration};
+use crate::group_code::GroupCodeFormat;
+use crate::protocol::IceServer;
+use crate::rate_limit::RateLimit;
+
+/// Smallest member limit a group can have
+pub const MIN_GROUP_SIZE: usize = 2;
+/// Longest value accepted for any `*_SECS` setting, a week
+const MAX_DURATION_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Tunables for the chat server, read from the environment at startup
+#[derive(Debug, Clone)]
+pub struct ChatServerConfig {
+    /// How long a user with interests waits for a shared-interest match before being matched randomly
+    pub interest_match_timeout: Duration,
+    /// Whether `next` puts both users straight back into the matching queue
+    pub requeue_on_next: bool,
+    /// How long two users who skipped each other are kept from being re-matched
+    pub recent_partner_cooldown: Duration,
+    /// Per-connection budget for `send_message`
+    pub message_rate: RateLimit,
+    /// Per-connection budget for `typing_start` and `typing_stop`
+    pub typing_rate: RateLimit,
+    /// Per-connection budget for WebRTC offers, answers and ICE candidates
+    pub signaling_rate: RateLimit,
+    /// Per-connection budget for every other event
+    pub control_rate: RateLimit,
+    /// Per-IP budgets are the per-connection ones multiplied by this factor
+    pub ip_rate_factor: f64,
+    /// Rate-limited events tolerated within `rate_violation_window` before disconnecting
+    pub max_rate_violations: usize,
+    pub rate_violation_window: Duration,
+    /// Take the client IP from `Forwarded`/`X-Forwarded-For`; only enable behind a trusted proxy
+    pub trust_forwarded_for: bool,
+    /// Messages buffered per connection before typing events are dropped and the client is disconnected
+    pub outbound_queue_capacity: usize,
+    /// Commands buffered for the chat server before handlers wait
+    pub command_queue_capacity: usize,
+    /// How long clients get after the shutdown warning before their connections are closed
+    pub shutdown_drain_timeout: Duration,
+    /// Reconnect delay suggested to clients in the shutdown warning
+    pub shutdown_reconnect_after: Duration,
+    /// How long a user's chat state is kept after their connection drops so that they can resume
+    pub resume_grace_period: Duration,
+    /// STUN/TURN servers sent to clients in `chat_started`
+    pub ice_servers: Vec<IceServer>,
+    /// Largest group in which members may set up a WebRTC mesh
+    pub webrtc_max_group_size: usize,
+    /// Bearer token for the admin HTTP API, which is disabled when unset
+    pub admin_token: Option<String>,
+    /// Most members a group may have; creators may choose a lower limit
+    pub max_group_size: usize,
+    /// Length of new group codes and whether they end in a check character
+    pub group_code: GroupCodeFormat,
+}
+
+impl Default for ChatServerConfig {
+    fn default() -> Self {
+        Self {
+            interest_match_timeout: Duration::from_secs(10),
+            requeue_on_next: true,
+            recent_partner_cooldown: Duration::from_secs(300),
+            message_rate: RateLimit::new(2.0, 10.0),
+            typing_rate: RateLimit::new(4.0, 10.0),
+            signaling_rate: RateLimit::new(10.0, 50.0),
+            control_rate: RateLimit::new(1.0, 5.0),
+            ip_rate_factor: 4.0,
+            max_rate_violations: 20,
+            rate_violation_window: Duration::from_secs(10),
+            trust_forwarded_for: false,
+            outbound_queue_capacity: 256,
+            command_queue_capacity: 4096,
+            shutdown_drain_timeout: Duration::from_secs(10),
+            shutdown_reconnect_after: Duration::from_secs(5),
+            resume_grace_period: Duration::from_secs(30),
+            ice_servers: vec![IceServer {
+                urls: vec!["stun:stun.l.google.com:19302".to_string()],
+                username: None,
+                credential: None,
+            }],
+            webrtc_max_group_size: 4,
+            admin_token: None,
+            max_group_size: 20,
+            group_code: GroupCodeFormat::new(6, false),
+        }
+    }
+}
+
+impl ChatServerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            interest_match_timeout: secs_env("INTEREST_MATCH_TIMEOUT_SECS", default.interest_match_timeout),
+            requeue_on_next: env_or("REQUEUE_ON_NEXT", default.requeue_on_next),
+            recent_partner_cooldown: secs_env("RECENT_PARTNER_COOLDOWN_SECS", default.recent_partner_cooldown),
+            message_rate: rate_limit_env("MESSAGES", default.message_rate),
+            typing_rate: rate_limit_env("TYPING", default.typing_rate),
+            signaling_rate: rate_limit_env("SIGNALING", default.signaling_rate),
+            control_rate: rate_limit_env("CONTROL", default.control_rate),
+            ip_rate_factor: positive_env("RATE_LIMIT_IP_FACTOR", default.ip_rate_factor),
+            max_rate_violations: env_or("RATE_LIMIT_MAX_VIOLATIONS", default.max_rate_violations),
+            rate_violation_window: secs_env("RATE_LIMIT_VIOLATION_WINDOW_SECS", default.rate_violation_window),
+            trust_forwarded_for: env_or("TRUST_FORWARDED_FOR", default.trust_forwarded_for),
+            outbound_queue_capacity: env_or("OUTBOUND_QUEUE_CAPACITY", default.outbound_queue_capacity).max(1),
+            command_queue_capacity: env_or("COMMAND_QUEUE_CAPACITY", default.command_queue_capacity).max(1),
+            shutdown_drain_timeout: secs_env("SHUTDOWN_DRAIN_TIMEOUT_SECS", default.shutdown_drain_timeout),
+            shutdown_reconnect_after: secs_env("SHUTDOWN_RECONNECT_AFTER_SECS", default.shutdown_reconnect_after),
+            resume_grace_period: secs_env("RESUME_GRACE_PERIOD_SECS", default.resume_grace_period),
+            ice_servers: ice_servers_env(default.ice_servers),
+            webrtc_max_group_size: env_or("WEBRTC_MAX_GROUP_SIZE", default.webrtc_max_group_size),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            max_group_size: env_or("MAX_GROUP_SIZE", default.max_group_size).max(MIN_GROUP_SIZE),
+            group_code: GroupCodeFormat::new(
+                env_or("GROUP_CODE_LENGTH", default.group_code.length),
+                env_or("GROUP_CODE_CHECKSUM", default.group_code.checksum),
+            ),
+        }
+    }
+}
+
+// Read ICE_SERVERS as a comma-separated list of `stun:` and `turn:` URLs. TURN_USERNAME and
+// TURN_CREDENTIAL apply to the TURN servers.
+fn ice_servers_env(default: Vec<IceServer>) -> Vec<IceServer> {
+    let Ok(urls) = env::var("ICE_SERVERS") else {
+        return default;
+    };
+    let (turn, stun): (Vec<String>, Vec<String>) = urls
+        .split(',')
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .partition(|url| url.starts_with("turn:") || url.starts_with("turns:"));
+    let mut servers = Vec::new();
+    if !stun.is_empty() {
+        servers.push(IceServer { urls: stun, username: None, credential: None });
+    }
+    if !turn.is_empty() {
+        servers.push(IceServer {
+            urls: turn,
+            username: env::var("TURN_USERNAME").ok().filter(|value| !value.is_empty()),
+            credential: env::var("TURN_CREDENTIAL").ok().filter(|value| !value.is_empty()),
+        });
+    }
+    servers
+}
+
+// Read RATE_LIMIT_<NAME>_PER_SEC and RATE_LIMIT_<NAME>_BURST
+fn rate_limit_env(name: &str, default: RateLimit) -> RateLimit {
+    RateLimit::new(
+        positive_env(&format!("RATE_LIMIT_{}_PER_SEC", name), default.per_second),
+        positive_env(&format!("RATE_LIMIT_{}_BURST", name), default.burst),
+    )
+}
+
+// Read a number of seconds, clamped so that adding it to the current time cannot overflow
+fn secs_env(key: &str, default: Duration) -> Duration {
+    let secs = env_or(key, default.as_secs());
+    if secs > MAX_DURATION_SECS {
+        log::warn!("{} is over the maximum of {} seconds; using the maximum", key, MAX_DURATION_SECS);
+    }
+    Duration::from_secs(secs.min(MAX_DURATION_SECS))
+}
+
+// Read a number that must be finite and greater than zero, falling back to a default otherwise
+fn positive_env(key: &str, default: f64) -> f64 {
+    let value = env_or(key, default);
+    if value.is_finite() && value > 0.0 {
+        value
+    } else {
+        log::warn!("{} must be a positive number, got {}; using default", key, value);
+        default
+    }
+}
+
+// Read and parse an environment variable, falling back to a default when unset or invalid
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            log::warn!("Invalid value for {}: {:?}; using default", key, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,144 @@
+//! Group codes that are easy to read out and type: a fixed alphabet without look-alike
+//! characters, and an optional check character so that a mistyped code is rejected
+//! instead of leading into someone else's group.
+
+use rand::Rng;
+
+/// Digits and upper-case letters without 0, 1, I, L and O. Its size, 31, is prime,
+/// which the check character relies on.
+const ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+
+/// Shortest configurable code
+pub const MIN_LENGTH: usize = 4;
+/// Longest configurable code; must stay below the alphabet size for the checksum to catch every typo
+pub const MAX_LENGTH: usize = 16;
+
+/// Shape of the group codes handed out by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCodeFormat {
+    /// Number of random characters
+    pub length: usize,
+    /// Whether a check character is appended to the random ones
+    pub checksum: bool,
+}
+
+impl GroupCodeFormat {
+    pub fn new(length: usize, checksum: bool) -> Self {
+        Self {
+            length: length.clamp(MIN_LENGTH, MAX_LENGTH),
+            checksum,
+        }
+    }
+
+    pub fn generate(&self, rng: &mut impl Rng) -> String {
+        let mut code: Vec<u8> = (0..self.length)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())])
+            .collect();
+        if self.checksum {
+            code.push(check_char(&code));
+        }
+        // ALPHABET is ASCII
+        code.into_iter().map(char::from).collect()
+    }
+
+    /// The canonical form of a code typed by a user, or `None` if no code of this format
+    /// could look like it. Case and surrounding whitespace are ignored.
+    pub fn parse(&self, input: &str) -> Option<String> {
+        let code = input.trim().to_ascii_uppercase();
+        let bytes = code.as_bytes();
+        let expected_len = self.length + usize::from(self.checksum);
+        if bytes.len() != expected_len || !bytes.iter().all(|c| ALPHABET.contains(c)) {
+            return None;
+        }
+        if self.checksum {
+            let (body, check) = bytes.split_at(self.length);
+            if check[0] != check_char(body) {
+                return None;
+            }
+        }
+        Some(code)
+    }
+}
+
+// Position-weighted sum of the character values modulo the (prime) alphabet size. Any single
+// wrong character or swap of two adjacent characters changes the sum.
+fn check_char(code: &[u8]) -> u8 {
+    let sum: usize = code
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i + 1) * ALPHABET.iter().position(|a| a == c).unwrap_or(0))
+        .sum();
+    ALPHABET[sum % ALPHABET.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn codes(format: GroupCodeFormat) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(21);
+        (0..50).map(|_| format.generate(&mut rng)).collect()
+    }
+
+    #[test]
+    fn generated_codes_parse_back() {
+        let formats = [GroupCodeFormat::new(6, false), GroupCodeFormat::new(6, true), GroupCodeFormat::new(MAX_LENGTH, true)];
+        for format in formats {
+            for code in codes(format) {
+                assert_eq!(code.len(), format.length + usize::from(format.checksum));
+                assert_eq!(format.parse(&code), Some(code.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_ignores_case_and_surrounding_whitespace() {
+        let format = GroupCodeFormat::new(6, true);
+        for code in codes(format) {
+            assert_eq!(format.parse(&format!(" \t{}\n", code.to_lowercase())), Some(code));
+        }
+    }
+
+    #[test]
+    fn checksum_rejects_every_single_substitution() {
+        let format = GroupCodeFormat::new(6, true);
+        for code in codes(format) {
+            for i in 0..code.len() {
+                for &c in ALPHABET.iter().filter(|&&c| c != code.as_bytes()[i]) {
+                    let mut typo = code.clone().into_bytes();
+                    typo[i] = c;
+                    let typo = String::from_utf8(typo).unwrap();
+                    assert_eq!(format.parse(&typo), None, "{} accepted for {}", typo, code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_rejects_every_adjacent_transposition() {
+        let format = GroupCodeFormat::new(6, true);
+        for code in codes(format) {
+            for i in 0..code.len() - 1 {
+                let mut swapped = code.clone().into_bytes();
+                if swapped[i] == swapped[i + 1] {
+                    continue;
+                }
+                swapped.swap(i, i + 1);
+                let swapped = String::from_utf8(swapped).unwrap();
+                assert_eq!(format.parse(&swapped), None, "{} accepted for {}", swapped, code);
+            }
+        }
+    }
+
+    #[test]
+    fn look_alike_characters_are_rejected() {
+        let format = GroupCodeFormat::new(6, false);
+        let code = codes(format).remove(0);
+        for look_alike in ['0', 'O', 'o', '1', 'I', 'i', 'L', 'l'] {
+            let typo: String = std::iter::once(look_alike).chain(code.chars().skip(1)).collect();
+            assert_eq!(format.parse(&typo), None, "{} accepted", typo);
+        }
+    }
+}
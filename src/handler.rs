@@ -1,205 +1,295 @@
-use std::{
-    pin::pin,
-    time::{Duration, Instant},
-};
-use actix_ws::{Message, MessageStream, Session};
-use futures_util::{
-    future::{select, Either},
-    StreamExt as _,
-};
-use tokio::{sync::mpsc, time::interval};
-use serde_json::Value;
-use crate::server::{ChatServerHandle, ConnId, EncryptedMessage, UserProfile};
+use std::time::{Duration, Instant};
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use futures_util::StreamExt as _;
+use tokio::time::interval;
+use crate::protocol::{self, ClientMessage, DeliveryStatus, ServerMessage};
+use crate::rate_limit::{EventKind, RateLimiter, Verdict};
+use crate::bans::Ban;
+use crate::server::{ChatServerError, ChatServerHandle, ClientInfo, CloseCause, ConnId, DisconnectReason, Signal};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(serde::Deserialize)]
-struct ClientEvent {
-    event: String,
-    data: Value,
-}
-
-#[derive(serde::Deserialize)]
-struct SendMessageData {
-    message: EncryptedMessage,
-    is_group_chat: bool,
-    group_code: Option<String>,
-}
-
-#[derive(serde::Deserialize)]
-struct TypingData {
-    is_group_chat: bool,
-    group_code: Option<String>,
-}
-
 /// Handle WebSocket connections, process messages, and maintain connection health
 pub async fn chat_ws(
     chat_server: ChatServerHandle,
     mut session: Session,
     mut msg_stream: MessageStream,
+    mut rate_limiter: RateLimiter,
+    client: ClientInfo,
 ) {
     log::info!("WebSocket connection established");
     
     let mut last_heartbeat = Instant::now();
     let mut interval = interval(HEARTBEAT_INTERVAL);
     
-    // Create a channel for this connection
-    let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
-    
-    // Register with the chat server and get a connection ID
-    let conn_id = chat_server.connect(conn_tx).await;
+    // Register with the chat server and get a connection ID and its channels
+    let mut conn = match chat_server.connect(client).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::error!("Failed to register connection: {}", err);
+            let _ = session.close(Some(unavailable_close_reason(err))).await;
+            return;
+        }
+    };
+    // The ID this socket acts as, which changes if the client resumes an earlier session
+    let mut conn_id = conn.id.clone();
     log::info!("Client connected with ID: {}", conn_id);
     
-    let close_reason = loop {
-        // Set up the futures we'll select between
-        let tick = pin!(interval.tick());
-        let msg_rx = pin!(conn_rx.recv());
-        let ws_msg = pin!(msg_stream.next());
-        
-        let messages = pin!(select(ws_msg, msg_rx));
-        
-        match select(messages, tick).await {
-            // Messages from client
-            Either::Left((Either::Left((Some(Ok(msg)), _)), _)) => {
-                log::debug!("Received message: {:?}", msg);
-                last_heartbeat = Instant::now();
-                
-                match msg {
-                    Message::Ping(bytes) => {
-                        if let Err(e) = session.pong(&bytes).await {
-                            log::error!("Failed to send pong: {}", e);
-                            break None;
+    let (cause, close_reason) = loop {
+        tokio::select! {
+            ws_msg = msg_stream.next() => match ws_msg {
+                // Messages from client
+                Some(Ok(msg)) => {
+                    log::debug!("Received message: {:?}", msg);
+                    last_heartbeat = Instant::now();
+                    
+                    match msg {
+                        Message::Ping(bytes) => {
+                            if let Err(e) = session.pong(&bytes).await {
+                                log::error!("Failed to send pong: {}", e);
+                                break (CloseCause::Error, None);
+                            }
                         }
+                        Message::Pong(_) => {
+                            // Heartbeat received, nothing to do
+                        }
+                        Message::Text(text) => {
+                            match process_text_msg(&chat_server, &mut rate_limiter, &text, &mut conn_id).await {
+                                Ok(Some(reply)) => {
+                                    let Ok(reply) = serde_json::to_string(&reply) else {
+                                        log::error!("Failed to serialize reply to client");
+                                        continue;
+                                    };
+                                    if let Err(e) = session.text(reply).await {
+                                        log::error!("Failed to send reply to client: {}", e);
+                                        break (CloseCause::Error, None);
+                                    }
+                                }
+                                Ok(None) => {}
+                                Err((cause, reason)) => break (cause, Some(reason)),
+                            }
+                        }
+                        Message::Binary(_) => {
+                            log::warn!("Unexpected binary message");
+                        }
+                        Message::Close(reason) => break (CloseCause::ClientClosed, reason),
+                        Message::Continuation(_) => {
+                            log::warn!("Received continuation frame, which should be handled by actix-ws");
+                        }
+                        Message::Nop => {}
                     }
-                    Message::Pong(_) => {
-                        // Heartbeat received, nothing to do
-                    }
-                    Message::Text(text) => {
-                        process_text_msg(&chat_server, &text, conn_id.clone()).await;
-                    }
-                    Message::Binary(_) => {
-                        log::warn!("Unexpected binary message");
+                }
+                
+                // Client WebSocket stream error
+                Some(Err(err)) => {
+                    log::error!("WebSocket error: {}", err);
+                    break (CloseCause::Error, None);
+                }
+                
+                // Client WebSocket stream ended
+                None => {
+                    log::info!("WebSocket connection closed by client");
+                    break (CloseCause::ClientClosed, None);
+                }
+            },
+            
+            chat_msg = conn.rx.recv() => match chat_msg {
+                // Messages from chat server to be sent to client
+                Some(chat_msg) => {
+                    if let Err(e) = session.text(chat_msg).await {
+                        log::error!("Failed to send message to client: {}", e);
+                        break (CloseCause::Error, None);
                     }
-                    Message::Close(reason) => break reason,
-                    Message::Continuation(_) => {
-                        log::warn!("Received continuation frame, which should be handled by actix-ws");
+                }
+                
+                // All connection's message senders were dropped, either because the chat server
+                // closed this connection or because it panicked
+                None => {
+                    if let Ok(reason) = conn.close_rx.try_recv() {
+                        break (CloseCause::Server(reason), Some(close_reason_for(reason)));
                     }
-                    Message::Nop => {}
+                    log::error!("All connection message senders were dropped; chat server may have panicked");
+                    break (CloseCause::Error, None);
                 }
-            }
-            
-            // Client WebSocket stream error
-            Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
-                log::error!("WebSocket error: {}", err);
-                break None;
-            }
+            },
             
-            // Client WebSocket stream ended
-            Either::Left((Either::Left((None, _)), _)) => {
-                log::info!("WebSocket connection closed by client");
-                break None;
-            }
-            
-            // Messages from chat server to be sent to client
-            Either::Left((Either::Right((Some(chat_msg), _)), _)) => {
-                if let Err(e) = session.text(chat_msg).await {
-                    log::error!("Failed to send message to client: {}", e);
-                    break None;
+            // Chat server closed this connection
+            reason = &mut conn.close_rx => match reason {
+                Ok(reason) => break (CloseCause::Server(reason), Some(close_reason_for(reason))),
+                Err(_) => {
+                    log::error!("Chat server dropped the close channel; chat server may have panicked");
+                    break (CloseCause::Error, None);
                 }
-            }
-            
-            // All connection's message senders were dropped
-            Either::Left((Either::Right((None, _)), _)) => {
-                log::error!("All connection message senders were dropped; chat server may have panicked");
-                break None;
-            }
+            },
             
             // Heartbeat tick
-            Either::Right((_, _)) => {
+            _ = interval.tick() => {
                 // Check if client is still responsive
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
                     log::info!("Client has not sent heartbeat in over {:?}; disconnecting", CLIENT_TIMEOUT);
-                    break None;
+                    break (CloseCause::HeartbeatTimeout, None);
                 }
                 
                 // Send heartbeat ping
                 if let Err(e) = session.ping(b"").await {
                     log::error!("Failed to send ping: {}", e);
-                    break None;
+                    break (CloseCause::Error, None);
                 }
             }
         }
     };
     
     // Clean up when the connection ends
-    chat_server.disconnect(conn_id);
+    if let Err(err) = chat_server.disconnect(conn_id, conn.id, cause).await {
+        log::error!("Failed to unregister connection: {}", err);
+    }
     log::info!("WebSocket connection closed");
     
     // Attempt to close connection gracefully
     let _ = session.close(close_reason).await;
 }
 
+/// Tell a banned client why it cannot connect, then close the socket
+pub async fn reject_banned(mut session: Session, ban: Ban) {
+    if let Ok(event) = serde_json::to_string(&ban.event()) {
+        let _ = session.text(event).await;
+    }
+    let _ = session.close(Some(close_reason_for(DisconnectReason::Banned))).await;
+}
+
+// WebSocket close frame sent when the chat server closes a connection
+fn close_reason_for(reason: DisconnectReason) -> CloseReason {
+    let (code, description) = match reason {
+        DisconnectReason::SlowConsumer => (CloseCode::Policy, "slow consumer"),
+        DisconnectReason::ServerShutdown => (CloseCode::Restart, "server shutting down"),
+        DisconnectReason::Replaced => (CloseCode::Normal, "session resumed on another connection"),
+        DisconnectReason::Banned => (CloseCode::Policy, "banned"),
+        DisconnectReason::Kicked => (CloseCode::Policy, "kicked by an administrator"),
+    };
+    CloseReason {
+        code,
+        description: Some(description.to_string()),
+    }
+}
+
+// WebSocket close frame sent when the chat server could not handle a request
+fn unavailable_close_reason(err: ChatServerError) -> CloseReason {
+    CloseReason {
+        code: CloseCode::Error,
+        description: Some(err.to_string()),
+    }
+}
+
+// Dispatch a client frame to the chat server, returning an event for the client if it was
+// rejected, or why the client should be disconnected
 async fn process_text_msg(
     chat_server: &ChatServerHandle,
+    rate_limiter: &mut RateLimiter,
     text: &str,
-    conn_id: ConnId,
-) {
-    // Try to parse the message as a ClientEvent
-    if let Ok(client_event) = serde_json::from_str::<ClientEvent>(text) {
-        match client_event.event.as_str() {
-            "join_chat" => {
-                if let Ok(profile) = serde_json::from_value::<UserProfile>(client_event.data) {
-                    log::info!("User joining chat: {}", profile.username);
-                    chat_server.join_chat(conn_id, profile).await;
-                } else {
-                    log::error!("Failed to parse join_chat data");
-                }
-            }
-            "send_message" => {
-                if let Ok(data) = serde_json::from_value::<SendMessageData>(client_event.data) {
-                    chat_server.send_message(
-                        conn_id,
-                        data.message,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse send_message data");
-                }
-            }
-            "typing_start" => {
-                if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
-                    chat_server.typing_start(
-                        conn_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse typing_start data");
-                }
-            }
-            "typing_stop" => {
-                if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
-                    chat_server.typing_stop(
-                        conn_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse typing_stop data");
+    conn_id: &mut ConnId,
+) -> Result<Option<ServerMessage>, (CloseCause, CloseReason)> {
+    let parsed = protocol::parse_client_message(text);
+    let kind = match &parsed {
+        Ok((client_message, _)) => EventKind::of(client_message),
+        Err(_) => EventKind::Control,
+    };
+    match rate_limiter.check(kind) {
+        Verdict::Allow => {}
+        Verdict::Limited { retry_after } => {
+            let retry_after_ms = retry_after.as_millis().try_into().unwrap_or(u64::MAX);
+            // Messages with an id are answered with an ack so the client can mark them as failed
+            if let Ok((ClientMessage::SendMessage(data), _)) = &parsed {
+                if let Some(message_id) = &data.message_id {
+                    return Ok(Some(ServerMessage::MessageAck {
+                        message_id: message_id.clone(),
+                        status: DeliveryStatus::RateLimited,
+                        retry_after_ms: Some(retry_after_ms),
+                    }));
                 }
             }
-            "disconnect_chat" => {
-                chat_server.disconnect_chat(conn_id).await;
-            }
-            _ => {
-                log::warn!("Unknown event type: {}", client_event.event);
-            }
+            let event = match &parsed {
+                Ok((_, meta)) => meta.event.clone(),
+                Err(_) => String::new(),
+            };
+            return Ok(Some(ServerMessage::RateLimited { event, retry_after_ms }));
+        }
+        Verdict::Abusive => {
+            log::warn!("Disconnecting {} for exceeding rate limits", conn_id);
+            return Err((CloseCause::RateLimited, CloseReason {
+                code: CloseCode::Policy,
+                description: Some("rate limit exceeded".to_string()),
+            }));
         }
-    } else {
-        log::error!("Failed to parse message as ClientEvent: {}", text);
     }
-} 
\ No newline at end of file
+
+    let (client_message, meta) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            log::warn!("Rejected client message: {}", text);
+            return Ok(Some(error));
+        }
+    };
+
+    let result = match client_message {
+        ClientMessage::JoinChat(profile) => {
+            log::info!("User joining chat: {}", profile.username);
+            chat_server.join_chat(conn_id.clone(), profile).await
+        }
+        ClientMessage::SendMessage(data) => chat_server.send_message(conn_id.clone(), data).await,
+        ClientMessage::KeyExchange(data) => chat_server.key_exchange(conn_id.clone(), data).await,
+        ClientMessage::WebrtcOffer(data) => {
+            chat_server.signal(conn_id.clone(), data.route, Signal::Offer { sdp: data.sdp }).await
+        }
+        ClientMessage::WebrtcAnswer(data) => {
+            chat_server.signal(conn_id.clone(), data.route, Signal::Answer { sdp: data.sdp }).await
+        }
+        ClientMessage::IceCandidate(data) => {
+            let signal = Signal::IceCandidate {
+                candidate: data.candidate,
+                sdp_mid: data.sdp_mid,
+                sdp_m_line_index: data.sdp_m_line_index,
+            };
+            chat_server.signal(conn_id.clone(), data.route, signal).await
+        }
+        ClientMessage::ReportUser(data) => chat_server.report_user(conn_id.clone(), data).await,
+        ClientMessage::MessageDelivered(data) => {
+            chat_server.message_delivered(conn_id.clone(), data.message_id).await
+        }
+        ClientMessage::TypingStart(data) => {
+            chat_server.typing_start(
+                conn_id.clone(),
+                data.is_group_chat,
+                data.group_code,
+            ).await
+        }
+        ClientMessage::TypingStop(data) => {
+            chat_server.typing_stop(
+                conn_id.clone(),
+                data.is_group_chat,
+                data.group_code,
+            ).await
+        }
+        ClientMessage::DisconnectChat {} => chat_server.disconnect_chat(conn_id.clone()).await.map(Ok),
+        ClientMessage::Next {} => chat_server.next(conn_id.clone()).await,
+        ClientMessage::KickMember(data) => chat_server.kick_member(conn_id.clone(), data.target).await,
+        ClientMessage::MuteMember(data) => chat_server.mute_member(conn_id.clone(), data).await,
+        ClientMessage::PromoteMember(data) => chat_server.promote_member(conn_id.clone(), data.target).await,
+        ClientMessage::ListGroups(query) => chat_server.browse_groups(conn_id.clone(), query).await.map(Ok),
+        ClientMessage::Resume { resume_token } => {
+            chat_server.resume(conn_id.clone(), resume_token).await.map(|result| {
+                result.map(|resumed| *conn_id = resumed)
+            })
+        }
+    };
+
+    match result {
+        Ok(result) => Ok(result.err().map(|err| meta.error(err))),
+        Err(err) => {
+            log::error!("Closing {}: {}", conn_id, err);
+            Err((CloseCause::ServerUnavailable, unavailable_close_reason(err)))
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Snapshots of chat server state returned by the admin API.
+
+use serde::Serialize;
+use crate::protocol::GroupVisibility;
+use crate::server::{ConnId, RoomId};
+
+/// What a connection is currently doing
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// Connected but has not sent `join_chat`
+    Connected,
+    Waiting,
+    Paired,
+    InGroup,
+    /// Joined, but neither waiting, paired nor in a group
+    Idle,
+    /// The socket dropped and the session is being kept for resumption
+    Detached,
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub conn_id: ConnId,
+    pub state: SessionState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Seconds since the socket connected; absent for detached sessions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connected_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<UserInfo>,
+}
+
+#[derive(Serialize)]
+pub struct UserInfo {
+    pub user_id: String,
+    pub username: String,
+    pub gender: String,
+    pub preference: String,
+    pub room_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partner: Option<ConnId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_code: Option<RoomId>,
+}
+
+/// Users waiting for a 1:1 partner with the same gender and preference
+#[derive(Serialize)]
+pub struct WaitingBucket {
+    pub gender: String,
+    pub preference: String,
+    pub users: Vec<WaitingUser>,
+}
+
+#[derive(Serialize)]
+pub struct WaitingUser {
+    pub conn_id: ConnId,
+    pub username: String,
+    pub waiting_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct PairInfo {
+    pub conn_ids: [ConnId; 2],
+    pub usernames: [String; 2],
+}
+
+#[derive(Serialize)]
+pub struct GroupInfo {
+    pub code: RoomId,
+    pub member_count: usize,
+    pub max_size: usize,
+    pub visibility: GroupVisibility,
+    pub owner: String,
+    pub usernames: Vec<String>,
+}
@@ -1,11 +1,30 @@
+mod admin;
+mod bans;
+mod config;
+mod group_code;
+mod introspection;
+mod matching;
+mod metrics;
+mod moderation;
+mod protocol;
+mod rate_limit;
 mod server;
 mod handler;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use actix_cors::Cors;
-use server::ChatServer;
-use shuttle_actix_web::ShuttleActixWeb;
-use std::env;
+use config::ChatServerConfig;
+use protocol::ListGroupsData;
+use rate_limit::{IpRateLimiter, RateLimiter};
+use serde::Deserialize;
+use server::{ChatServer, ChatServerError, ChatServerHandle, ClientInfo};
+use std::{env, net::{IpAddr, SocketAddr}, time::Duration};
+
+#[cfg(not(any(feature = "shuttle", feature = "standalone")))]
+compile_error!("enable either the `shuttle` or the `standalone` feature");
+
+/// How long health checks wait for the chat server to answer
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
 // ### Server Setup
 
@@ -13,37 +32,119 @@ async fn index() -> impl Responder {
     "Socket.io server for Random Tune Harmony chat is running"
 }
 
+// JSON schema of the WebSocket protocol, for the frontend's generated types
+async fn protocol_schema() -> impl Responder {
+    HttpResponse::Ok().json(protocol::schema())
+}
+
+// Liveness: the chat server is processing commands
+async fn healthz(srv: web::Data<ChatServerHandle>) -> HttpResponse {
+    if srv.ping(HEALTH_CHECK_TIMEOUT).await {
+        HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "degraded",
+            "reason": "chat server did not respond",
+        }))
+    }
+}
+
+// Readiness: the chat server is processing commands and accepting new connections
+async fn readyz(srv: web::Data<ChatServerHandle>) -> HttpResponse {
+    if srv.is_shutting_down() {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "shutting_down",
+        }));
+    }
+    healthz(srv).await
+}
+
+// Prometheus metrics gathered from the chat server
+async fn metrics(srv: web::Data<ChatServerHandle>) -> Result<HttpResponse, ChatServerError> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(srv.metrics().await?))
+}
+
+// Public groups, for rendering a lobby before joining
+async fn groups(srv: web::Data<ChatServerHandle>, query: web::Query<ListGroupsData>) -> Result<HttpResponse, ChatServerError> {
+    Ok(HttpResponse::Ok().json(srv.group_directory(query.into_inner()).await?))
+}
+
+// The client's IP address, from proxy headers only when they are trusted
+fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = req.connection_info().realip_remote_addr().and_then(|addr| {
+            addr.parse::<IpAddr>()
+                .or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()
+        });
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+// Query parameters of the WebSocket upgrade request
+#[derive(Deserialize)]
+struct WsQuery {
+    /// Browsers cannot set headers on WebSocket requests, so the device fingerprint comes in the URL
+    device_fingerprint: Option<String>,
+}
+
 async fn ws_route(
     req: HttpRequest,
     body: web::Payload,
+    query: web::Query<WsQuery>,
     srv: web::Data<server::ChatServerHandle>,
+    config: web::Data<ChatServerConfig>,
+    ip_limiter: web::Data<IpRateLimiter>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    // Refuse new connections once shutdown has begun
+    if srv.is_shutting_down() {
+        return Ok(HttpResponse::ServiceUnavailable().body("Server is shutting down"));
+    }
+
+    let ip = client_ip(&req, config.trust_forwarded_for);
+    let client = ClientInfo {
+        ip,
+        device_fingerprint: query.into_inner().device_fingerprint.filter(|device| !device.is_empty()),
+    };
+    let ban = srv.check_ban(client.clone()).await?;
+    let rate_limiter = RateLimiter::new(&config, ip.map(|ip| (ip, ip_limiter.get_ref().clone())));
+
     // Upgrade the HTTP connection to a WebSocket connection
     let (response, session, stream) = actix_ws::handle(&req, body)?;
-    
+
+    // Banned clients get a `banned` event explaining why before the socket is closed
+    if let Some(ban) = ban {
+        log::info!("Rejected connection from banned client (ban {})", ban.id);
+        actix_web::rt::spawn(handler::reject_banned(session, ban));
+        return Ok(response);
+    }
+
     // Spawn a task to handle the WebSocket connection
     let chat_server = srv.get_ref().clone();
-    actix_web::rt::spawn(handler::chat_ws(chat_server, session, stream));
-    
+    actix_web::rt::spawn(handler::chat_ws(chat_server, session, stream, rate_limiter, client));
+
     Ok(response)
 }
 
-#[shuttle_runtime::main]
-async fn main() -> ShuttleActixWeb<impl FnOnce(&mut web::ServiceConfig) + Send + Clone + 'static> {
-    // Create a chat server
-    let chat_server = ChatServer::start();
-    
-    // Define the config function to set up routes
-    let config = move |cfg: &mut web::ServiceConfig| {
-        // Get allowed origin from environment variable or use default
-        let allowed_origin = env::var("ALLOWED_ORIGIN").unwrap_or_else(|_| "http://localhost:3000".to_string());
-        
+/// Route and middleware configuration shared by the Shuttle and standalone builds
+fn configure_app(
+    chat_server: ChatServerHandle,
+    config: ChatServerConfig,
+    allowed_origin: String,
+) -> impl Fn(&mut web::ServiceConfig) + Send + Clone + 'static {
+    let ip_limiter = IpRateLimiter::new(&config);
+    move |cfg: &mut web::ServiceConfig| {
         log::info!("Configuring CORS with allowed origin: {}", allowed_origin);
-        
+
         // Configure CORS
         let cors = Cors::default()
             .allowed_origin(&allowed_origin)
-            .allowed_methods(vec!["GET", "POST"])
+            .allowed_methods(vec!["GET", "POST", "DELETE"])
             .allowed_headers(vec![
                 actix_web::http::header::AUTHORIZATION,
                 actix_web::http::header::ACCEPT,
@@ -51,16 +152,152 @@ async fn main() -> ShuttleActixWeb<impl FnOnce(&mut web::ServiceConfig) + Send +
             ])
             .supports_credentials()
             .max_age(3600);
-        
+
         // With Shuttle, we need to use a different approach for middleware
         cfg.service(
             web::scope("")
                 .wrap(cors)
                 .app_data(web::Data::new(chat_server.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(ip_limiter.clone()))
                 .route("/", web::get().to(index))
                 .route("/ws/", web::get().to(ws_route))
+                .route("/protocol/schema", web::get().to(protocol_schema))
+                .route("/metrics", web::get().to(metrics))
+                .route("/groups", web::get().to(groups))
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz))
+                .service(web::scope("/admin").configure(admin::configure))
         );
-    };
-    
+    }
+}
+
+// Get allowed origin from environment variable or use default
+fn allowed_origin_from_env() -> String {
+    env::var("ALLOWED_ORIGIN").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+// Resolve on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// `standalone` takes precedence, so that it can be enabled on top of the default features
+#[cfg(all(feature = "shuttle", not(feature = "standalone")))]
+#[shuttle_runtime::main]
+async fn main() -> shuttle_actix_web::ShuttleActixWeb<impl FnOnce(&mut web::ServiceConfig) + Send + Clone + 'static> {
+    // Create a chat server
+    let server_config = ChatServerConfig::from_env();
+    let chat_server = ChatServer::start(server_config.clone());
+
+    // Warn and drain clients on shutdown. Best effort: the Shuttle runtime owns the process
+    // and may stop it before the drain period ends.
+    let shutdown_server = chat_server.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        if let Err(err) = shutdown_server.shutdown().await {
+            log::error!("Failed to drain chat clients: {}", err);
+        }
+    });
+
+    // Define the config function to set up routes
+    let config = configure_app(chat_server, server_config, allowed_origin_from_env());
+
     Ok(config.into())
-}
\ No newline at end of file
+}
+
+/// Command-line options for the standalone build; each falls back to an environment variable
+#[cfg(feature = "standalone")]
+struct StandaloneArgs {
+    host: String,
+    port: u16,
+    allowed_origin: String,
+}
+
+#[cfg(feature = "standalone")]
+impl StandaloneArgs {
+    const USAGE: &'static str = "usage: notchat-server [--host <addr>] [--port <port>] [--allowed-origin <origin>]";
+
+    // Parse `--host`, `--port` and `--allowed-origin`, defaulting to HOST, PORT and ALLOWED_ORIGIN
+    fn parse() -> Result<Self, String> {
+        let mut args = Self {
+            host: env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
+            port: match env::var("PORT") {
+                Ok(port) => port.parse().map_err(|_| format!("invalid PORT: {}", port))?,
+                Err(_) => 8080,
+            },
+            allowed_origin: allowed_origin_from_env(),
+        };
+
+        let mut argv = env::args().skip(1);
+        while let Some(flag) = argv.next() {
+            if flag == "--help" || flag == "-h" {
+                return Err(Self::USAGE.to_string());
+            }
+            let value = argv.next().ok_or_else(|| format!("missing value for {}\n{}", flag, Self::USAGE))?;
+            match flag.as_str() {
+                "--host" => args.host = value,
+                "--port" => args.port = value.parse().map_err(|_| format!("invalid port: {}", value))?,
+                "--allowed-origin" => args.allowed_origin = value,
+                _ => return Err(format!("unknown argument: {}\n{}", flag, Self::USAGE)),
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(feature = "standalone")]
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+
+    let args = match StandaloneArgs::parse() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    // Create a chat server
+    let server_config = ChatServerConfig::from_env();
+    let chat_server = ChatServer::start(server_config.clone());
+    let config = configure_app(chat_server.clone(), server_config, args.allowed_origin);
+
+    log::info!("Listening on {}:{}", args.host, args.port);
+    let server = actix_web::HttpServer::new(move || actix_web::App::new().configure(config.clone()))
+        .bind((args.host.as_str(), args.port))?
+        .disable_signals()
+        .run();
+
+    // On Ctrl-C or SIGTERM, drain chat clients before stopping the HTTP server
+    let server_handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        if let Err(err) = chat_server.shutdown().await {
+            log::error!("Failed to drain chat clients: {}", err);
+        }
+        server_handle.stop(true).await;
+    });
+
+    server.await
+}
@@ -0,0 +1,129 @@
+use std::collections::{HashMap, VecDeque};
+use crate::server::ConnId;
+
+/// Preference value that accepts a partner of any gender
+pub const ANY_PREFERENCE: &str = "any";
+
+/// Whether someone with `preference` is willing to be matched with someone of `gender`
+pub fn accepts(preference: &str, gender: &str) -> bool {
+    preference == ANY_PREFERENCE || preference == gender
+}
+
+/// Whether two users are willing to be matched with each other
+pub fn mutually_compatible(gender_a: &str, preference_a: &str, gender_b: &str, preference_b: &str) -> bool {
+    accepts(preference_a, gender_b) && accepts(preference_b, gender_a)
+}
+
+// Users with the same gender and preference share a bucket
+type BucketKey = (String, String);
+
+/// Users waiting for a 1:1 partner, bucketed by (gender, preference) so that a mutual
+/// query only has to look at the handful of compatible buckets. Each bucket is FIFO.
+#[derive(Default)]
+pub struct WaitQueue {
+    buckets: HashMap<BucketKey, VecDeque<ConnId>>,
+    index: HashMap<ConnId, BucketKey>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, conn: ConnId, gender: &str, preference: &str) {
+        self.remove(&conn);
+        let key = (gender.to_string(), preference.to_string());
+        self.buckets.entry(key.clone()).or_default().push_back(conn.clone());
+        self.index.insert(conn, key);
+    }
+
+    pub fn remove(&mut self, conn: &ConnId) -> bool {
+        let Some(key) = self.index.remove(conn) else {
+            return false;
+        };
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            bucket.retain(|id| id != conn);
+            if bucket.is_empty() {
+                self.buckets.remove(&key);
+            }
+        }
+        true
+    }
+
+    /// Waiting users who would accept, and be accepted by, someone with this gender and preference
+    pub fn candidates<'a>(&'a self, gender: &'a str, preference: &'a str) -> impl Iterator<Item = &'a ConnId> + 'a {
+        self.buckets
+            .iter()
+            .filter(move |((other_gender, other_preference), _)| {
+                mutually_compatible(gender, preference, other_gender, other_preference)
+            })
+            .flat_map(|(_, bucket)| bucket.iter())
+    }
+
+    /// Every waiting user, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &ConnId> {
+        self.index.keys()
+    }
+
+    /// Each non-empty bucket's gender, preference and users, oldest first
+    pub fn buckets(&self) -> impl Iterator<Item = (&str, &str, &VecDeque<ConnId>)> {
+        self.buckets
+            .iter()
+            .map(|((gender, preference), bucket)| (gender.as_str(), preference.as_str(), bucket))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(queue: &WaitQueue, gender: &str, preference: &str) -> Vec<ConnId> {
+        let mut found: Vec<ConnId> = queue.candidates(gender, preference).cloned().collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn any_matches_any() {
+        assert!(mutually_compatible("male", ANY_PREFERENCE, "female", ANY_PREFERENCE));
+        assert!(mutually_compatible("male", ANY_PREFERENCE, "male", ANY_PREFERENCE));
+
+        let mut queue = WaitQueue::new();
+        queue.push("a".to_owned(), "female", ANY_PREFERENCE);
+        queue.push("b".to_owned(), "male", ANY_PREFERENCE);
+        assert_eq!(candidates(&queue, "other", ANY_PREFERENCE), ["a", "b"]);
+    }
+
+    #[test]
+    fn one_sided_preference_is_rejected() {
+        // Being open to anyone is not enough when the other side wants a different gender
+        assert!(!mutually_compatible("female", "female", "male", ANY_PREFERENCE));
+        assert!(!mutually_compatible("male", ANY_PREFERENCE, "female", "female"));
+        assert!(mutually_compatible("female", "female", "female", ANY_PREFERENCE));
+
+        let mut queue = WaitQueue::new();
+        queue.push("picky".to_owned(), "female", "female");
+        queue.push("open".to_owned(), "female", ANY_PREFERENCE);
+        assert_eq!(candidates(&queue, "male", ANY_PREFERENCE), ["open"]);
+        assert_eq!(candidates(&queue, "female", "female"), ["open", "picky"]);
+    }
+
+    #[test]
+    fn push_and_remove_rebucket_users() {
+        let mut queue = WaitQueue::new();
+        queue.push("a".to_owned(), "male", "female");
+        assert_eq!(candidates(&queue, "female", ANY_PREFERENCE), ["a"]);
+
+        // Pushing again moves the user to the bucket of their new preference
+        queue.push("a".to_owned(), "male", "male");
+        assert!(candidates(&queue, "female", ANY_PREFERENCE).is_empty());
+        assert_eq!(candidates(&queue, "male", ANY_PREFERENCE), ["a"]);
+        assert_eq!(queue.iter().count(), 1);
+        assert_eq!(queue.buckets().count(), 1);
+
+        assert!(queue.remove(&"a".to_owned()));
+        assert!(!queue.remove(&"a".to_owned()));
+        assert_eq!(queue.iter().count(), 0);
+        assert_eq!(queue.buckets().count(), 0);
+    }
+}
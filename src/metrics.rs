@@ -0,0 +1,175 @@
+//! Counters kept by the chat server actor, rendered in the Prometheus text format.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+/// Bucket bounds in seconds for the time users wait to be matched
+const MATCH_WAIT_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+/// Bucket bounds in seconds for the time commands spend queued for the actor
+const COMMAND_LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+/// Upper bounds of the group size buckets
+const GROUP_SIZE_BUCKETS: &[f64] = &[2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+
+/// Cumulative histogram with fixed bucket bounds
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, self.sum, name, self.count);
+    }
+}
+
+/// Counters updated by the actor as it processes commands
+pub struct ServerMetrics {
+    pub typing_events_dropped: u64,
+    pub slow_consumer_disconnects: u64,
+    /// Times the actor panicked and was restarted; carried over into the new instance
+    pub actor_restarts: u64,
+    /// Relayed client events by event name
+    pub messages_relayed: BTreeMap<&'static str, u64>,
+    /// Ended WebSocket connections by cause
+    pub disconnects: BTreeMap<&'static str, u64>,
+    pub match_wait: Histogram,
+    pub command_latency: Histogram,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self {
+            typing_events_dropped: 0,
+            slow_consumer_disconnects: 0,
+            actor_restarts: 0,
+            messages_relayed: BTreeMap::new(),
+            disconnects: BTreeMap::new(),
+            match_wait: Histogram::new(MATCH_WAIT_BUCKETS),
+            command_latency: Histogram::new(COMMAND_LATENCY_BUCKETS),
+        }
+    }
+}
+
+/// Point-in-time values sampled by the actor when metrics are requested
+pub struct QueueSnapshot {
+    pub command_queue_depth: usize,
+    pub outbound_queue_depth_total: usize,
+    pub outbound_queue_depth_max: usize,
+    pub outbound_queue_capacity: usize,
+}
+
+/// Point-in-time chat state sampled by the actor when metrics are requested
+pub struct StateSnapshot {
+    pub connections: usize,
+    pub detached_sessions: usize,
+    pub joined_users: usize,
+    pub waiting_by_preference: BTreeMap<String, usize>,
+    pub pairs: usize,
+    pub group_sizes: Vec<usize>,
+}
+
+impl ServerMetrics {
+    pub fn render(&self, queues: &QueueSnapshot, state: &StateSnapshot) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "chat_connections", "Open WebSocket connections", state.connections as f64);
+        gauge(&mut out, "chat_detached_sessions", "Sessions kept for resumption after their socket dropped", state.detached_sessions as f64);
+        gauge(&mut out, "chat_joined_users", "Users who have sent join_chat", state.joined_users as f64);
+        labeled(
+            &mut out,
+            "chat_waiting_users",
+            "Users waiting for a 1:1 partner, by preference",
+            "gauge",
+            "preference",
+            state.waiting_by_preference.iter().map(|(preference, count)| (preference.as_str(), *count as f64)),
+        );
+        gauge(&mut out, "chat_pairs", "Active 1:1 chats", state.pairs as f64);
+        gauge(&mut out, "chat_groups", "Active group chats", state.group_sizes.len() as f64);
+        let _ = writeln!(out, "# HELP chat_group_size Groups by member count\n# TYPE chat_group_size gauge");
+        for bound in GROUP_SIZE_BUCKETS {
+            let count = state.group_sizes.iter().filter(|size| **size as f64 <= *bound).count();
+            let _ = writeln!(out, "chat_group_size{{le=\"{}\"}} {}", bound, count);
+        }
+        let _ = writeln!(out, "chat_group_size{{le=\"+Inf\"}} {}", state.group_sizes.len());
+
+        gauge(&mut out, "chat_command_queue_depth", "Commands waiting to be processed by the chat server", queues.command_queue_depth as f64);
+        gauge(&mut out, "chat_outbound_queue_depth_total", "Messages queued for delivery across all connections", queues.outbound_queue_depth_total as f64);
+        gauge(&mut out, "chat_outbound_queue_depth_max", "Deepest per-connection outbound queue", queues.outbound_queue_depth_max as f64);
+        gauge(&mut out, "chat_outbound_queue_capacity", "Capacity of each per-connection outbound queue", queues.outbound_queue_capacity as f64);
+
+        labeled(
+            &mut out,
+            "chat_messages_relayed_total",
+            "Client events relayed to other users, by event",
+            "counter",
+            "event",
+            self.messages_relayed.iter().map(|(event, count)| (*event, *count as f64)),
+        );
+        labeled(
+            &mut out,
+            "chat_disconnects_total",
+            "Ended WebSocket connections, by cause",
+            "counter",
+            "cause",
+            self.disconnects.iter().map(|(cause, count)| (*cause, *count as f64)),
+        );
+        counter(&mut out, "chat_typing_events_dropped_total", "Typing events dropped because the recipient's queue was nearly full", self.typing_events_dropped);
+        counter(&mut out, "chat_slow_consumer_disconnects_total", "Connections closed because their outbound queue filled up", self.slow_consumer_disconnects);
+        counter(&mut out, "chat_actor_restarts_total", "Times the chat server panicked and was restarted", self.actor_restarts);
+        self.match_wait.render(&mut out, "chat_match_wait_seconds", "Time users waited before being matched with a partner");
+        self.command_latency.render(&mut out, "chat_command_queue_latency_seconds", "Time commands spent queued before the chat server processed them");
+        out
+    }
+}
+
+pub fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+pub fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+// A metric family with one sample per label value
+fn labeled<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    label: &str,
+    samples: impl Iterator<Item = (&'a str, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    for (value, sample) in samples {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape_label(value), sample);
+    }
+}
+
+// Label values are client-controlled, so quotes, backslashes and newlines must be escaped
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
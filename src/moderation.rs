@@ -0,0 +1,178 @@
+//! Abuse reports filed by users, held in memory until a moderator resolves them
+//! through the admin API.
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use serde::{Deserialize, Serialize};
+use crate::protocol::{EncryptedMessage, ReportReason};
+
+/// Oldest reports are dropped once the queue holds this many
+const MAX_REPORTS: usize = 10_000;
+/// Oldest reports are also dropped once the reports' details and messages add up to this many bytes
+const MAX_QUEUE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Review state of a report
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    Open,
+    /// Action was taken against the reported user
+    Resolved,
+    /// The report was reviewed and no action was needed
+    Dismissed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Report {
+    pub id: u64,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    pub status: ReportStatus,
+    pub reporter_user_id: String,
+    pub reporter_username: String,
+    /// Address the report was filed from, when known
+    pub reporter_ip: Option<IpAddr>,
+    pub reported_user_id: String,
+    pub reported_username: String,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    /// Encrypted envelopes the reporter chose to disclose
+    pub messages: Vec<EncryptedMessage>,
+    /// Moderator's note when the report was closed
+    pub resolution_note: Option<String>,
+}
+
+/// A report as filed by the chat server, before it is assigned an id
+pub struct NewReport {
+    pub reporter_user_id: String,
+    pub reporter_username: String,
+    pub reporter_ip: Option<IpAddr>,
+    pub reported_user_id: String,
+    pub reported_username: String,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub messages: Vec<EncryptedMessage>,
+}
+
+impl NewReport {
+    /// Bytes taken up by the details and the attached envelopes
+    pub fn size(&self) -> usize {
+        report_size(self.details.as_deref(), &self.messages)
+    }
+}
+
+#[derive(Default)]
+pub struct ModerationQueue {
+    reports: VecDeque<Report>,
+    next_id: u64,
+    /// Sum of the sizes of the queued reports
+    bytes: usize,
+}
+
+impl ModerationQueue {
+    pub fn submit(&mut self, report: NewReport) -> u64 {
+        self.next_id += 1;
+        let size = report.size();
+        while !self.reports.is_empty() && (self.reports.len() >= MAX_REPORTS || self.bytes + size > MAX_QUEUE_BYTES) {
+            if let Some(dropped) = self.reports.pop_front() {
+                self.bytes -= report_size(dropped.details.as_deref(), &dropped.messages);
+            }
+        }
+        self.bytes += size;
+        self.reports.push_back(Report {
+            id: self.next_id,
+            created_at: unix_now(),
+            status: ReportStatus::Open,
+            reporter_user_id: report.reporter_user_id,
+            reporter_username: report.reporter_username,
+            reporter_ip: report.reporter_ip,
+            reported_user_id: report.reported_user_id,
+            reported_username: report.reported_username,
+            reason: report.reason,
+            details: report.details,
+            messages: report.messages,
+            resolution_note: None,
+        });
+        self.next_id
+    }
+
+    /// Reports with the given status, oldest first
+    pub fn list(&self, status: Option<ReportStatus>, offset: usize, limit: usize) -> Vec<Report> {
+        self.reports
+            .iter()
+            .filter(|report| status.is_none_or(|status| report.status == status))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Open reports filed by a user or from an address
+    pub fn open_reports_by(&self, user_id: &str, ip: Option<IpAddr>) -> usize {
+        self.reports
+            .iter()
+            .filter(|report| report.status == ReportStatus::Open)
+            .filter(|report| report.reporter_user_id == user_id || (ip.is_some() && report.reporter_ip == ip))
+            .count()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Report> {
+        self.reports.iter().find(|report| report.id == id).cloned()
+    }
+
+    /// Set the status of a report, returning it if it exists
+    pub fn resolve(&mut self, id: u64, status: ReportStatus, note: Option<String>) -> Option<Report> {
+        let report = self.reports.iter_mut().find(|report| report.id == id)?;
+        report.status = status;
+        report.resolution_note = note;
+        Some(report.clone())
+    }
+}
+
+fn report_size(details: Option<&str>, messages: &[EncryptedMessage]) -> usize {
+    let details = details.map_or(0, str::len);
+    details + messages.iter().map(|message| message.encrypted.len() + message.nonce.len()).sum::<usize>()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(user_id: &str, ip: Option<IpAddr>) -> NewReport {
+        NewReport {
+            reporter_user_id: user_id.to_owned(),
+            reporter_username: user_id.to_owned(),
+            reporter_ip: ip,
+            reported_user_id: "reported".to_owned(),
+            reported_username: "reported".to_owned(),
+            reason: ReportReason::Spam,
+            details: None,
+            messages: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn open_reports_are_counted_per_user_and_per_address() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut queue = ModerationQueue::default();
+        queue.submit(report("alice", Some(ip)));
+        let second = queue.submit(report("bob", Some(ip)));
+        queue.submit(report("carol", None));
+
+        assert_eq!(queue.open_reports_by("alice", None), 1);
+        assert_eq!(queue.open_reports_by("dave", Some(ip)), 2);
+        assert_eq!(queue.open_reports_by("alice", Some(ip)), 2);
+        queue.resolve(second, ReportStatus::Dismissed, None);
+        assert_eq!(queue.open_reports_by("dave", Some(ip)), 1);
+    }
+}
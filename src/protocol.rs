@@ -0,0 +1,565 @@
+//! WebSocket wire protocol. Every frame is a JSON object of the form
+//! `{ "event": "<name>", "data": <payload> }`; the enums below are the single
+//! definition of which events exist and what their payloads look like.
+//! Client frames may also carry a top-level `request_id`, which is echoed
+//! back in any `error` event caused by that frame.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::server::RoomId;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct EncryptedMessage {
+    pub encrypted: String,
+    pub nonce: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub username: String,
+    /// Gender the user wants to be matched with, or `"any"`
+    pub preference: String,
+    pub gender: String,
+    /// `"group"` for group chat, anything else for 1:1
+    pub room_type: String,
+    pub group_code: Option<String>,
+    /// `"create"`, `"join"` (with `group_code`) or `"random"`
+    pub group_join_method: Option<String>,
+    /// Member limit for a group being created; defaults to, and may not exceed, the server's limit
+    #[serde(default)]
+    pub group_max_size: Option<usize>,
+    /// Who may join a group being created; defaults to `unlisted`
+    #[serde(default)]
+    pub group_visibility: Option<GroupVisibility>,
+    /// Password for creating a `password` group, or for joining one by code
+    #[serde(default)]
+    pub group_password: Option<String>,
+    /// Title of a group being created, shown in the public group directory
+    #[serde(default)]
+    pub group_title: Option<String>,
+    /// Language of a group being created, such as `"en"`, shown in the public group directory
+    #[serde(default)]
+    pub group_language: Option<String>,
+    /// Interest tags used to prefer partners with something in common
+    #[serde(default)]
+    pub interests: Vec<String>,
+    /// Stable identifier of the client device, used for bans
+    #[serde(default)]
+    pub device_fingerprint: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SendMessageData {
+    pub message: EncryptedMessage,
+    pub is_group_chat: bool,
+    pub group_code: Option<String>,
+    /// Client-chosen id echoed in `message_ack` and passed on to recipients for receipts
+    #[serde(default)]
+    pub message_id: Option<String>,
+}
+
+/// A public key published for end-to-end encryption. The server relays it without interpreting it.
+#[derive(Deserialize, JsonSchema)]
+pub struct KeyExchangeData {
+    pub public_key: String,
+    pub fingerprint: String,
+    pub is_group_chat: bool,
+    pub group_code: Option<String>,
+}
+
+/// A chat member's published public key
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct MemberKey {
+    pub username: String,
+    pub public_key: String,
+    pub fingerprint: String,
+}
+
+/// Which page of the public group directory to return
+#[derive(Deserialize, JsonSchema)]
+pub struct ListGroupsData {
+    #[serde(default)]
+    pub offset: usize,
+    /// Page size; defaults to 20 and is capped at 50
+    pub limit: Option<usize>,
+    /// Only list groups in this language
+    pub language: Option<String>,
+}
+
+/// A public group as shown in the group directory
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct PublicGroup {
+    pub code: RoomId,
+    pub member_count: usize,
+    pub max_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// A page of the public group directory
+#[derive(Serialize, JsonSchema)]
+pub struct GroupDirectoryPage {
+    pub groups: Vec<PublicGroup>,
+    /// Number of public groups matching the query, across all pages
+    pub total: usize,
+}
+
+/// Where a WebRTC signaling event is sent
+#[derive(Deserialize, JsonSchema)]
+pub struct SignalRoute {
+    #[serde(default)]
+    pub is_group_chat: bool,
+    pub group_code: Option<String>,
+    /// Username of the peer. Required in group chats; in 1:1 chats it must name the partner if given.
+    pub target: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SessionDescriptionData {
+    pub sdp: String,
+    #[serde(flatten)]
+    pub route: SignalRoute,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct IceCandidateData {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(flatten)]
+    pub route: SignalRoute,
+}
+
+/// A STUN or TURN server, in the shape of WebRTC's `RTCIceServer`
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Who may join a group
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupVisibility {
+    /// Anyone can join by code, and random joins may land in it
+    Public,
+    /// Only users who know the code can join
+    Unlisted,
+    /// Users need the code and the group's password
+    Password,
+}
+
+/// Why a user is being reported
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    Spam,
+    Harassment,
+    SexualContent,
+    Underage,
+    Violence,
+    Other,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ReportUserData {
+    pub reason: ReportReason,
+    /// Username of the reported group member; defaults to the current or last 1:1 partner
+    pub target: Option<String>,
+    pub details: Option<String>,
+    /// Received message envelopes the reporter chooses to disclose to moderators
+    #[serde(default)]
+    pub messages: Vec<EncryptedMessage>,
+}
+
+/// A member of the sender's group, for `kick_member` and `promote_member`
+#[derive(Deserialize, JsonSchema)]
+pub struct GroupMemberData {
+    /// Username of the member
+    pub target: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MuteMemberData {
+    /// Username of the member
+    pub target: String,
+    /// How long the member stays muted; 0 lifts an earlier mute
+    pub duration_secs: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct MessageDeliveredData {
+    pub message_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TypingData {
+    pub is_group_chat: bool,
+    pub group_code: Option<String>,
+}
+
+/// Events sent by the client
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ClientMessage {
+    JoinChat(UserProfile),
+    SendMessage(SendMessageData),
+    TypingStart(TypingData),
+    TypingStop(TypingData),
+    DisconnectChat {},
+    /// Skip the current partner and look for a new one
+    Next {},
+    /// Publish a public key to the partner, or to every member of the group
+    KeyExchange(KeyExchangeData),
+    /// Receipt for a `receive_message` shown to the user; only supported in 1:1 chats
+    MessageDelivered(MessageDeliveredData),
+    WebrtcOffer(SessionDescriptionData),
+    WebrtcAnswer(SessionDescriptionData),
+    IceCandidate(IceCandidateData),
+    /// Report the partner or a group member to moderators
+    ReportUser(ReportUserData),
+    /// Reclaim a session after a dropped connection; must be the first event on the new socket
+    Resume {
+        resume_token: String,
+    },
+    /// Remove a member from the sender's group; owner or moderator only
+    KickMember(GroupMemberData),
+    /// Stop a member of the sender's group from sending messages and typing events; owner or moderator only
+    MuteMember(MuteMemberData),
+    /// Make a member of the sender's group a moderator; owner only
+    PromoteMember(GroupMemberData),
+    /// Page through the public group directory; may be sent before `join_chat`
+    ListGroups(ListGroupsData),
+}
+
+/// Events sent by the server
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// First event on every connection; keep the token to resume after a dropped connection
+    Connected {
+        resume_token: String,
+        /// How long the session is kept after the connection drops
+        resume_grace_ms: u64,
+    },
+    /// The session was resumed; buffered events follow. The previous token is no longer valid.
+    Resumed {
+        resume_token: String,
+    },
+    ChatStarted {
+        #[serde(rename = "groupCode", skip_serializing_if = "Option::is_none")]
+        group_code: Option<RoomId>,
+        /// Interests both partners listed; only sent for 1:1 chats
+        #[serde(rename = "sharedInterests", skip_serializing_if = "Option::is_none")]
+        shared_interests: Option<Vec<String>>,
+        /// STUN/TURN servers for setting up WebRTC calls
+        #[serde(rename = "iceServers", skip_serializing_if = "Option::is_none")]
+        ice_servers: Option<Vec<IceServer>>,
+    },
+    WaitingForMatch {},
+    ReceiveMessage {
+        message: EncryptedMessage,
+        sender: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<String>,
+    },
+    /// Outcome of a `send_message` that carried a `message_id`
+    MessageAck {
+        message_id: String,
+        status: DeliveryStatus,
+        /// Only sent with `rate_limited`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// The partner or a group member published their public key
+    KeyExchange(MemberKey),
+    /// Keys already published in a group, sent after joining it
+    GroupKeyBundle(Vec<MemberKey>),
+    /// A group member rejoined with a key different from the one they used before
+    KeyChanged(MemberKey),
+    /// WebRTC signaling from the partner or a group member
+    WebrtcOffer {
+        sdp: String,
+        sender: String,
+    },
+    WebrtcAnswer {
+        sdp: String,
+        sender: String,
+    },
+    IceCandidate {
+        candidate: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sdp_mid: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sdp_m_line_index: Option<u16>,
+        sender: String,
+    },
+    /// The client is banned; the connection is closed after this event
+    Banned {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        /// Time left on the ban, absent for a permanent ban
+        #[serde(skip_serializing_if = "Option::is_none")]
+        remaining_ms: Option<u64>,
+    },
+    /// A `report_user` was filed for review
+    ReportReceived {
+        report_id: u64,
+    },
+    /// The partner received a message sent with `message_id`
+    MessageDelivered {
+        message_id: String,
+    },
+    TypingStarted {
+        /// Only sent in group chats
+        #[serde(skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+    },
+    TypingStopped {
+        /// Only sent in group chats
+        #[serde(skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+    },
+    PartnerDisconnected {},
+    /// The partner pressed `next`
+    PartnerSkipped {
+        requeued: bool,
+    },
+    /// An administrator closed the group; members must rejoin to keep chatting
+    GroupDissolved {},
+    UserJoinedGroup(String),
+    UserLeftGroup(String),
+    GroupMembersUpdate(Vec<String>),
+    /// Owner and moderators of the group; sent on joining and whenever they change
+    GroupRoles {
+        owner: String,
+        moderators: Vec<String>,
+    },
+    /// The owner left and the named member took over the group
+    GroupOwnerChanged {
+        username: String,
+    },
+    /// A member was removed from the group; also sent to the removed member
+    MemberKicked {
+        username: String,
+        by: String,
+    },
+    /// A member may not send messages or typing events for `duration_ms`; 0 means the mute was lifted
+    MemberMuted {
+        username: String,
+        by: String,
+        duration_ms: u64,
+    },
+    MemberPromoted {
+        username: String,
+        by: String,
+    },
+    /// A page of the public group directory, answering `list_groups`
+    GroupList(GroupDirectoryPage),
+    GroupNotFound {},
+    /// The group asked for in `join` has no free slots
+    GroupFull {},
+    /// The group asked for in `join` needs a password, and none or the wrong one was given
+    GroupPasswordRequired {},
+    /// The user was kicked from the group asked for in `join` and may not join it again
+    KickedFromGroup {},
+    Error(ErrorData),
+    /// The server is going away; clients should reconnect after the hinted delay
+    ServerShuttingDown {
+        reconnect_after_ms: u64,
+        /// How long until the connection is closed by the server
+        drain_timeout_ms: u64,
+    },
+    /// An event was dropped because the client exceeded its rate limit
+    RateLimited {
+        event: String,
+        retry_after_ms: u64,
+    },
+}
+
+impl ServerMessage {
+    /// Events that may be dropped rather than delivered to a client that is falling behind
+    pub fn is_droppable(&self) -> bool {
+        matches!(self, Self::TypingStarted { .. } | Self::TypingStopped { .. })
+    }
+}
+
+/// What happened to a sent message
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Queued for every recipient
+    Delivered,
+    /// There was no partner or other group member to deliver to
+    NoRecipient,
+    /// Dropped because the sender exceeded its rate limit
+    RateLimited,
+}
+
+/// Machine-readable reason carried by an `error` event
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame was not a JSON object with an `event` name
+    InvalidJson,
+    UnknownEvent,
+    /// The event is known but its `data` did not match the expected shape
+    InvalidPayload,
+    /// The event requires `join_chat` first
+    NotJoined,
+    /// The event is not valid in the user's current state
+    InvalidState,
+    /// The event targets a group the user is not a member of
+    NotGroupMember,
+    /// The resume token is unknown or its grace period has expired
+    ResumeFailed,
+    /// A signaling event named a peer that is not the partner or a member of the sender's group
+    InvalidTarget,
+    /// The sender's group role does not allow the action
+    Forbidden,
+    /// The sender was muted by the group owner or a moderator
+    Muted,
+    /// The sender already has as many open reports as a reporter may have
+    TooManyReports,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorData {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Name of the event that caused the error, when it could be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Why a client request was rejected
+#[derive(Debug)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn not_joined() -> Self {
+        Self::new(ErrorCode::NotJoined, "join_chat must be sent first")
+    }
+
+    pub fn into_event(self, event: Option<String>, request_id: Option<String>) -> ServerMessage {
+        ServerMessage::Error(ErrorData {
+            code: self.code,
+            message: self.message,
+            event,
+            request_id,
+        })
+    }
+}
+
+// Outer shape of every client frame, read before the payload is interpreted
+#[derive(Deserialize)]
+struct Envelope {
+    event: String,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    request_id: Option<String>,
+}
+
+/// Event name and request id of a parsed client frame, used to annotate errors
+pub struct RequestMeta {
+    pub event: String,
+    pub request_id: Option<String>,
+}
+
+impl RequestMeta {
+    pub fn error(&self, err: RequestError) -> ServerMessage {
+        err.into_event(Some(self.event.clone()), self.request_id.clone())
+    }
+}
+
+/// Parse a client text frame, or produce the `error` event describing what was wrong with it
+pub fn parse_client_message(text: &str) -> Result<(ClientMessage, RequestMeta), ServerMessage> {
+    let envelope: Envelope = serde_json::from_str(text).map_err(|err| {
+        RequestError::new(ErrorCode::InvalidJson, err.to_string()).into_event(None, None)
+    })?;
+    let meta = RequestMeta {
+        event: envelope.event,
+        request_id: envelope.request_id,
+    };
+
+    // Events without a payload may omit `data` entirely
+    let data = match envelope.data {
+        Value::Null => Value::Object(Default::default()),
+        data => data,
+    };
+    let frame = serde_json::json!({ "event": meta.event, "data": data });
+    match serde_json::from_value::<ClientMessage>(frame) {
+        Ok(message) => Ok((message, meta)),
+        Err(err) => {
+            let code = if is_known_event(&meta.event) {
+                ErrorCode::InvalidPayload
+            } else {
+                ErrorCode::UnknownEvent
+            };
+            Err(meta.error(RequestError::new(code, err.to_string())))
+        }
+    }
+}
+
+// Whether `event` names a client event. Only the tag is deserialized, so an unknown variant
+// error here is about the event name and not about an enum value inside the payload.
+fn is_known_event(event: &str) -> bool {
+    match serde_json::from_value::<ClientMessage>(serde_json::json!({ "event": event })) {
+        Ok(_) => true,
+        Err(err) => !err.to_string().starts_with("unknown variant"),
+    }
+}
+
+/// JSON schema of both directions of the protocol, for generating client types
+pub fn schema() -> serde_json::Value {
+    serde_json::json!({
+        "client": schemars::schema_for!(ClientMessage),
+        "server": schemars::schema_for!(ServerMessage),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The error code of a frame that fails to parse
+    fn error_code(text: &str) -> Value {
+        let Err(event) = parse_client_message(text) else {
+            panic!("{} should not parse", text);
+        };
+        serde_json::to_value(event).unwrap()["data"]["code"].clone()
+    }
+
+    #[test]
+    fn bad_enum_value_in_payload_is_invalid_payload() {
+        let report = r#"{"event":"report_user","data":{"reason":"rudeness"}}"#;
+        assert_eq!(error_code(report), "invalid_payload");
+        let join = r#"{"event":"join_chat","data":{"user_id":"abcdefgh","username":"","preference":"any",
+            "gender":"male","room_type":"group","group_code":null,"group_join_method":"create",
+            "group_visibility":"secret"}}"#;
+        assert_eq!(error_code(join), "invalid_payload");
+    }
+
+    #[test]
+    fn unknown_event_is_unknown_event() {
+        assert_eq!(error_code(r#"{"event":"launch_rockets","data":{}}"#), "unknown_event");
+        assert_eq!(error_code(r#"{"event":"launch_rockets"}"#), "unknown_event");
+    }
+}
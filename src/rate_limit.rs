@@ -0,0 +1,233 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use crate::config::ChatServerConfig;
+use crate::protocol::ClientMessage;
+
+/// How long an IP's buckets are kept after its last event
+const IP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sustained rate and burst size of a token bucket
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub const fn new(per_second: f64, burst: f64) -> Self {
+        Self { per_second, burst }
+    }
+
+    fn scaled(self, factor: f64) -> Self {
+        Self::new(self.per_second * factor, self.burst * factor)
+    }
+}
+
+/// Event classes that draw from separate budgets
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Message,
+    Typing,
+    Signaling,
+    Control,
+}
+
+impl EventKind {
+    pub fn of(message: &ClientMessage) -> Self {
+        match message {
+            ClientMessage::SendMessage(_) => Self::Message,
+            // Receipts are lightweight and sent at most once per received message
+            ClientMessage::TypingStart(_) | ClientMessage::TypingStop(_) | ClientMessage::MessageDelivered(_) => Self::Typing,
+            ClientMessage::WebrtcOffer(_) | ClientMessage::WebrtcAnswer(_) | ClientMessage::IceCandidate(_) => Self::Signaling,
+            _ => Self::Control,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self { tokens: limit.burst, updated: now }
+    }
+
+    // Take one token, or report how long until one is available
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.per_second > 0.0 {
+            Err(Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second).unwrap_or(Duration::MAX))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    // Give back a token taken for an event that was rejected anyway
+    fn refund(&mut self, limit: RateLimit) {
+        self.tokens = (self.tokens + 1.0).min(limit.burst);
+    }
+}
+
+/// One bucket per event kind
+struct Buckets {
+    limits: [RateLimit; 4],
+    buckets: [TokenBucket; 4],
+}
+
+impl Buckets {
+    fn new(limits: [RateLimit; 4], now: Instant) -> Self {
+        Self {
+            limits,
+            buckets: limits.map(|limit| TokenBucket::new(limit, now)),
+        }
+    }
+
+    fn try_take(&mut self, kind: EventKind, now: Instant) -> Result<(), Duration> {
+        let i = kind as usize;
+        self.buckets[i].try_take(self.limits[i], now)
+    }
+
+    fn refund(&mut self, kind: EventKind) {
+        let i = kind as usize;
+        self.buckets[i].refund(self.limits[i]);
+    }
+}
+
+fn limits(config: &ChatServerConfig) -> [RateLimit; 4] {
+    [config.message_rate, config.typing_rate, config.signaling_rate, config.control_rate]
+}
+
+/// Buckets shared by every connection from the same IP address
+#[derive(Clone)]
+pub struct IpRateLimiter {
+    limits: [RateLimit; 4],
+    inner: Arc<Mutex<IpBuckets>>,
+}
+
+struct IpBuckets {
+    by_ip: HashMap<IpAddr, Buckets>,
+    last_prune: Instant,
+}
+
+impl IpRateLimiter {
+    pub fn new(config: &ChatServerConfig) -> Self {
+        Self {
+            limits: limits(config).map(|limit| limit.scaled(config.ip_rate_factor)),
+            inner: Arc::new(Mutex::new(IpBuckets {
+                by_ip: HashMap::new(),
+                last_prune: Instant::now(),
+            })),
+        }
+    }
+
+    fn try_take(&self, ip: IpAddr, kind: EventKind, now: Instant) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if now.duration_since(inner.last_prune) >= IP_IDLE_TIMEOUT {
+            inner.by_ip.retain(|_, buckets| {
+                buckets.buckets.iter().any(|bucket| now.duration_since(bucket.updated) < IP_IDLE_TIMEOUT)
+            });
+            inner.last_prune = now;
+        }
+        let limits = self.limits;
+        inner
+            .by_ip
+            .entry(ip)
+            .or_insert_with(|| Buckets::new(limits, now))
+            .try_take(kind, now)
+    }
+}
+
+/// Result of checking an event against the limiter
+pub enum Verdict {
+    Allow,
+    /// Drop the event and tell the client when to retry
+    Limited { retry_after: Duration },
+    /// The client kept exceeding its budget and should be disconnected
+    Abusive,
+}
+
+/// Per-connection limiter, also charging the connection's IP address
+pub struct RateLimiter {
+    buckets: Buckets,
+    ip: Option<(IpAddr, IpRateLimiter)>,
+    violations: VecDeque<Instant>,
+    max_violations: usize,
+    violation_window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: &ChatServerConfig, ip: Option<(IpAddr, IpRateLimiter)>) -> Self {
+        Self {
+            buckets: Buckets::new(limits(config), Instant::now()),
+            ip,
+            violations: VecDeque::new(),
+            max_violations: config.max_rate_violations,
+            violation_window: config.rate_violation_window,
+        }
+    }
+
+    pub fn check(&mut self, kind: EventKind) -> Verdict {
+        let now = Instant::now();
+        // A connection whose IP is over budget keeps its own token, so the refused event
+        // does not count against it twice
+        let result = self.buckets.try_take(kind, now).and_then(|()| match &self.ip {
+            Some((ip, limiter)) => limiter.try_take(*ip, kind, now).inspect_err(|_| self.buckets.refund(kind)),
+            None => Ok(()),
+        });
+        let Err(retry_after) = result else {
+            return Verdict::Allow;
+        };
+
+        while self.violations.front().is_some_and(|at| now.duration_since(*at) > self.violation_window) {
+            self.violations.pop_front();
+        }
+        self.violations.push_back(now);
+        if self.violations.len() > self.max_violations {
+            Verdict::Abusive
+        } else {
+            Verdict::Limited { retry_after }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_saturates_for_tiny_rates() {
+        let limit = RateLimit::new(f64::MIN_POSITIVE, 0.0);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(limit, now);
+        assert_eq!(bucket.try_take(limit, now), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn event_refused_for_the_ip_keeps_the_connection_token() {
+        let config = ChatServerConfig {
+            message_rate: RateLimit::new(1e-9, 2.0),
+            ip_rate_factor: 0.5,
+            ..ChatServerConfig::default()
+        };
+        let ip_limiter = IpRateLimiter::new(&config);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut first = RateLimiter::new(&config, Some((ip, ip_limiter.clone())));
+        let mut second = RateLimiter::new(&config, Some((ip, ip_limiter)));
+
+        assert!(matches!(first.check(EventKind::Message), Verdict::Allow));
+        assert!(matches!(second.check(EventKind::Message), Verdict::Limited { .. }));
+        let tokens = second.buckets.buckets[EventKind::Message as usize].tokens;
+        assert!((tokens - 2.0).abs() < 1e-6, "connection bucket has {} tokens", tokens);
+    }
+}
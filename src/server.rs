@@ -1,34 +1,254 @@
-use std::collections::HashMap;
-use tokio::sync::{mpsc, oneshot};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::IpAddr,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{interval, sleep_until},
+};
+use futures_util::FutureExt as _;
 use uuid::Uuid;
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use crate::bans::{Ban, BanList, BanTarget};
+use crate::config::{ChatServerConfig, MIN_GROUP_SIZE};
+use crate::introspection::{GroupInfo, PairInfo, SessionInfo, SessionState, UserInfo, WaitingBucket, WaitingUser};
+use crate::matching::{WaitQueue, ANY_PREFERENCE};
+use crate::metrics::{QueueSnapshot, ServerMetrics, StateSnapshot};
+use crate::moderation::{ModerationQueue, NewReport, Report, ReportStatus};
+use crate::protocol::{
+    DeliveryStatus, EncryptedMessage, ErrorCode, GroupDirectoryPage, GroupVisibility, IceServer, KeyExchangeData,
+    ListGroupsData, MemberKey, MuteMemberData, PublicGroup, RequestError, ReportUserData, SendMessageData, ServerMessage,
+    SignalRoute, UserProfile,
+};
 
 // Type aliases for clarity
 pub type ConnId = String;
 pub type RoomId = String;
 pub type Msg = String;
 
-// Message types
-#[derive(Serialize, Deserialize, Clone)]
-pub struct EncryptedMessage {
-    pub encrypted: String,
-    pub nonce: String,
+/// Maximum number of interest tags kept per user
+const MAX_INTERESTS: usize = 10;
+/// Maximum length of a single interest tag
+const MAX_INTEREST_LEN: usize = 32;
+/// How often waiting users are re-checked for a match
+const MATCH_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum length of a client-supplied message id
+const MAX_MESSAGE_ID_LEN: usize = 64;
+/// Maximum length of a published public key
+const MAX_PUBLIC_KEY_LEN: usize = 4096;
+/// Maximum length of a public key fingerprint
+const MAX_FINGERPRINT_LEN: usize = 256;
+/// Maximum length of a WebRTC session description
+const MAX_SDP_LEN: usize = 16 * 1024;
+/// Maximum length of an ICE candidate line
+const MAX_ICE_CANDIDATE_LEN: usize = 1024;
+/// Maximum length of the media stream id of an ICE candidate
+const MAX_SDP_MID_LEN: usize = 256;
+/// Maximum number of message envelopes attached to a report
+const MAX_REPORTED_MESSAGES: usize = 20;
+/// Maximum length of the free-text details of a report
+const MAX_REPORT_DETAILS_LEN: usize = 1000;
+/// Maximum length of the ciphertext of a message envelope attached to a report
+const MAX_REPORTED_CIPHERTEXT_LEN: usize = 4096;
+/// Maximum length of the nonce of a message envelope attached to a report
+const MAX_REPORTED_NONCE_LEN: usize = 256;
+/// Maximum size of the details and attached envelopes of a report together
+const MAX_REPORT_BYTES: usize = 32 * 1024;
+/// Open reports a user, or a single address, may have waiting for review
+const MAX_OPEN_REPORTS_PER_REPORTER: usize = 5;
+/// Maximum length of a device fingerprint
+const MAX_DEVICE_FINGERPRINT_LEN: usize = 128;
+/// Maximum length of a group password
+const MAX_GROUP_PASSWORD_LEN: usize = 128;
+/// Maximum length in characters of a group title
+const MAX_GROUP_TITLE_LEN: usize = 80;
+/// Maximum length of a group language tag
+const MAX_GROUP_LANGUAGE_LEN: usize = 16;
+/// Public groups listed per page when the client does not ask for a size
+const DEFAULT_GROUP_PAGE_SIZE: usize = 20;
+/// Most public groups listed per page
+const MAX_GROUP_PAGE_SIZE: usize = 50;
+/// Longest mute a group owner or moderator can impose
+const MAX_MUTE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Preferences reported as their own metric label; anything else is counted as "other"
+const METRIC_PREFERENCES: &[&str] = &[ANY_PREFERENCE, "male", "female"];
+
+/// A WebRTC signaling payload relayed between peers
+pub enum Signal {
+    Offer { sdp: String },
+    Answer { sdp: String },
+    IceCandidate {
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_m_line_index: Option<u16>,
+    },
+}
+
+impl Signal {
+    fn validate(&self) -> Result<(), RequestError> {
+        let fields = match self {
+            Signal::Offer { sdp } | Signal::Answer { sdp } => vec![("sdp", sdp.len(), MAX_SDP_LEN)],
+            Signal::IceCandidate { candidate, sdp_mid, .. } => vec![
+                ("candidate", candidate.len(), MAX_ICE_CANDIDATE_LEN),
+                ("sdp_mid", sdp_mid.as_ref().map_or(0, String::len), MAX_SDP_MID_LEN),
+            ],
+        };
+        for (field, len, max) in fields {
+            if len > max {
+                return Err(RequestError::new(
+                    ErrorCode::InvalidPayload,
+                    format!("{} must be at most {} bytes", field, max),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn event_name(&self) -> &'static str {
+        match self {
+            Signal::Offer { .. } => "webrtc_offer",
+            Signal::Answer { .. } => "webrtc_answer",
+            Signal::IceCandidate { .. } => "ice_candidate",
+        }
+    }
+
+    fn into_event(self, sender: String) -> ServerMessage {
+        match self {
+            Signal::Offer { sdp } => ServerMessage::WebrtcOffer { sdp, sender },
+            Signal::Answer { sdp } => ServerMessage::WebrtcAnswer { sdp, sender },
+            Signal::IceCandidate { candidate, sdp_mid, sdp_m_line_index } => ServerMessage::IceCandidate {
+                candidate,
+                sdp_mid,
+                sdp_m_line_index,
+                sender,
+            },
+        }
+    }
+}
+
+/// Why the chat server closed a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client could not keep up with its outbound queue
+    SlowConsumer,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The client resumed this connection's session on a new socket
+    Replaced,
+    /// The client's user id, IP address or device was banned
+    Banned,
+    /// An administrator closed the connection
+    Kicked,
+}
+
+impl DisconnectReason {
+    fn label(self) -> &'static str {
+        match self {
+            DisconnectReason::SlowConsumer => "slow_consumer",
+            DisconnectReason::ServerShutdown => "server_shutdown",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::Kicked => "kicked",
+        }
+    }
+}
+
+/// Why a WebSocket task ended, reported to the chat server for metrics
+#[derive(Debug, Clone, Copy)]
+pub enum CloseCause {
+    /// The client sent a close frame or the stream ended
+    ClientClosed,
+    /// The client stopped answering heartbeats
+    HeartbeatTimeout,
+    /// Reading from or writing to the socket failed
+    Error,
+    /// The client kept exceeding its rate limit
+    RateLimited,
+    /// The chat server closed the connection
+    Server(DisconnectReason),
+    /// The chat server stopped or failed to answer a command
+    ServerUnavailable,
+}
+
+impl CloseCause {
+    fn label(self) -> &'static str {
+        match self {
+            CloseCause::ClientClosed => "client_closed",
+            CloseCause::HeartbeatTimeout => "heartbeat_timeout",
+            CloseCause::Error => "error",
+            CloseCause::RateLimited => "rate_limited",
+            CloseCause::Server(reason) => reason.label(),
+            CloseCause::ServerUnavailable => "server_unavailable",
+        }
+    }
+}
+
+/// Failure to get an answer from the chat server actor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatServerError {
+    /// The actor has stopped and its command queue is closed
+    Stopped,
+    /// The actor dropped the command without answering, usually because it panicked
+    NoResponse,
+}
+
+impl fmt::Display for ChatServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatServerError::Stopped => write!(f, "chat server has stopped"),
+            ChatServerError::NoResponse => write!(f, "chat server did not answer"),
+        }
+    }
+}
+
+impl std::error::Error for ChatServerError {}
+
+// HTTP handlers answer 503 when the chat server cannot be reached
+impl actix_web::ResponseError for ChatServerError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Where a connection comes from, for enforcing bans
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub device_fingerprint: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct UserProfile {
-    pub user_id: String,
-    pub username: String,
-    pub preference: String,
-    pub gender: String,
-    pub room_type: String,
-    pub group_code: Option<String>,
-    pub group_join_method: Option<String>,
+/// Channels handed to a WebSocket task when it registers with the chat server
+pub struct Connection {
+    pub id: ConnId,
+    /// Messages to forward to the client
+    pub rx: mpsc::Receiver<Msg>,
+    /// Fires when the chat server wants the connection closed
+    pub close_rx: oneshot::Receiver<DisconnectReason>,
 }
 
 // Data structures
+struct Session {
+    tx: mpsc::Sender<Msg>,
+    close_tx: Option<oneshot::Sender<DisconnectReason>>,
+    socket_id: ConnId, // connection id first assigned to this socket; differs after a resume
+    resume_token: String,
+    client: ClientInfo,
+    connected_at: Instant,
+}
+
+// A joined user whose socket dropped, kept for the resume grace period
+struct Detached {
+    resume_token: String,
+    expires: Instant,
+    buffer: Vec<Msg>, // messages to deliver once the session is resumed
+}
+
 #[allow(dead_code)]
 struct User {
     id: ConnId, // socket id
@@ -39,516 +259,2737 @@ struct User {
     room_type: String,
     partner_id: Option<ConnId>,
     group_id: Option<RoomId>,
+    interests: Vec<String>,
+    waiting_since: Option<Instant>,
+    last_partner: Option<(String, String)>, // user_id and username of the latest 1:1 partner, for reports
+    device_fingerprint: Option<String>,
 }
 
 struct Group {
     code: RoomId,
     members: Vec<ConnId>, // socket ids
     usernames: Vec<String>,
+    max_size: usize,
+    visibility: GroupVisibility,
+    password: Option<String>,
+    title: Option<String>,
+    language: Option<String>,
+    owner: ConnId,
+    moderators: Vec<ConnId>, // in order of promotion, which is the order of succession
+    muted: HashMap<String, Instant>, // when each mute ends by user_id, kept after members leave
+    kicked: HashSet<String>, // user_ids that may not join again
+    keys: HashMap<ConnId, MemberKey>, // keys published by current members
+    fingerprints: HashMap<String, String>, // last fingerprint by user_id, kept after members leave
+}
+
+// Rank of a group member; members may only moderate those ranked below them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum GroupRole {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl Group {
+    fn role(&self, conn: &ConnId) -> GroupRole {
+        if &self.owner == conn {
+            GroupRole::Owner
+        } else if self.moderators.contains(conn) {
+            GroupRole::Moderator
+        } else {
+            GroupRole::Member
+        }
+    }
+
+    // How long a member stays muted, if they are
+    fn mute_remaining(&self, user_id: &str, now: Instant) -> Option<Duration> {
+        self.muted.get(user_id).and_then(|until| until.checked_duration_since(now)).filter(|left| !left.is_zero())
+    }
+}
+
+// Options chosen when a group is created
+struct GroupSettings {
+    max_size: usize,
+    visibility: GroupVisibility,
+    password: Option<String>,
+    title: Option<String>,
+    language: Option<String>,
+}
+
+// A command stamped with when it was queued, so the actor can measure queueing latency
+struct Queued {
+    command: Command,
+    queued_at: Instant,
 }
 
-// Server messages
-#[derive(Serialize)]
-pub struct ServerEvent {
-    pub event: String,
-    pub data: Value,
+// Sending half of the command queue
+#[derive(Debug, Clone)]
+struct CommandSender(mpsc::Sender<Queued>);
+
+impl CommandSender {
+    async fn send(&self, command: Command) -> Result<(), mpsc::error::SendError<Command>> {
+        self.0
+            .send(Queued { command, queued_at: Instant::now() })
+            .await
+            .map_err(|err| mpsc::error::SendError(err.0.command))
+    }
 }
 
 // Commands that can be sent to the chat server
 enum Command {
     Connect {
-        conn_tx: mpsc::UnboundedSender<Msg>,
+        conn_tx: mpsc::Sender<Msg>,
+        close_tx: oneshot::Sender<DisconnectReason>,
+        client: ClientInfo,
         res_tx: oneshot::Sender<ConnId>,
     },
+    Metrics {
+        res_tx: oneshot::Sender<String>,
+    },
+    Ping {
+        res_tx: oneshot::Sender<()>,
+    },
+    // Crash the actor, to exercise the restart path
+    #[cfg(test)]
+    Panic,
+    Shutdown {
+        res_tx: oneshot::Sender<()>,
+    },
     Disconnect {
         conn: ConnId,
+        socket: ConnId,
+        cause: CloseCause,
+    },
+    Resume {
+        conn: ConnId,
+        resume_token: String,
+        res_tx: oneshot::Sender<Result<ConnId, RequestError>>,
     },
     JoinChat {
         conn: ConnId,
         profile: UserProfile,
-        res_tx: oneshot::Sender<()>,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
     },
     SendMessage {
         conn: ConnId,
         message: EncryptedMessage,
         is_group_chat: bool,
         group_code: Option<String>,
-        res_tx: oneshot::Sender<()>,
+        message_id: Option<String>,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    MessageDelivered {
+        conn: ConnId,
+        message_id: String,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    KeyExchange {
+        conn: ConnId,
+        data: KeyExchangeData,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    Signal {
+        conn: ConnId,
+        route: SignalRoute,
+        signal: Signal,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    ReportUser {
+        conn: ConnId,
+        data: ReportUserData,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    ListReports {
+        status: Option<ReportStatus>,
+        offset: usize,
+        limit: usize,
+        res_tx: oneshot::Sender<Vec<Report>>,
+    },
+    GetReport {
+        id: u64,
+        res_tx: oneshot::Sender<Option<Report>>,
+    },
+    ResolveReport {
+        id: u64,
+        status: ReportStatus,
+        note: Option<String>,
+        res_tx: oneshot::Sender<Option<Report>>,
+    },
+    CheckBan {
+        client: ClientInfo,
+        res_tx: oneshot::Sender<Option<Ban>>,
+    },
+    ListBans {
+        res_tx: oneshot::Sender<Vec<Ban>>,
+    },
+    AddBan {
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: Option<String>,
+        res_tx: oneshot::Sender<Ban>,
+    },
+    RemoveBan {
+        id: u64,
+        res_tx: oneshot::Sender<Option<Ban>>,
+    },
+    ListSessions {
+        offset: usize,
+        limit: usize,
+        res_tx: oneshot::Sender<Vec<SessionInfo>>,
+    },
+    ListWaiting {
+        res_tx: oneshot::Sender<Vec<WaitingBucket>>,
+    },
+    ListPairs {
+        offset: usize,
+        limit: usize,
+        res_tx: oneshot::Sender<Vec<PairInfo>>,
+    },
+    ListGroups {
+        offset: usize,
+        limit: usize,
+        res_tx: oneshot::Sender<Vec<GroupInfo>>,
+    },
+    Kick {
+        conn: ConnId,
+        res_tx: oneshot::Sender<bool>,
+    },
+    DissolveGroup {
+        code: RoomId,
+        res_tx: oneshot::Sender<bool>,
     },
     TypingStart {
         conn: ConnId,
         is_group_chat: bool,
         group_code: Option<String>,
-        res_tx: oneshot::Sender<()>,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
     },
     TypingStop {
         conn: ConnId,
         is_group_chat: bool,
         group_code: Option<String>,
-        res_tx: oneshot::Sender<()>,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
     },
     DisconnectChat {
         conn: ConnId,
         res_tx: oneshot::Sender<()>,
     },
+    Next {
+        conn: ConnId,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    KickMember {
+        conn: ConnId,
+        target: String,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    MuteMember {
+        conn: ConnId,
+        data: MuteMemberData,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    PromoteMember {
+        conn: ConnId,
+        target: String,
+        res_tx: oneshot::Sender<Result<(), RequestError>>,
+    },
+    BrowseGroups {
+        conn: ConnId,
+        query: ListGroupsData,
+        res_tx: oneshot::Sender<()>,
+    },
+    GroupDirectory {
+        query: ListGroupsData,
+        res_tx: oneshot::Sender<GroupDirectoryPage>,
+    },
 }
 
 // Chat server implementation
 pub struct ChatServer {
-    sessions: HashMap<ConnId, mpsc::UnboundedSender<Msg>>,
+    sessions: HashMap<ConnId, Session>,
+    detached: HashMap<ConnId, Detached>,
+    resume_tokens: HashMap<String, ConnId>,
     users: HashMap<ConnId, User>,
-    waiting_users: HashMap<String, Vec<ConnId>>, // preference -> Vec<socket_id>
+    waiting_users: WaitQueue,
     groups: HashMap<RoomId, Group>,
+    // Pairs of user ids that skipped each other, and when, so they are not matched again
+    // within the cooldown even after reconnecting
+    recent_partners: HashMap<(String, String), Instant>,
+    reports: ModerationQueue,
+    bans: BanList,
+    config: ChatServerConfig,
+    metrics: ServerMetrics,
+    // Connections whose outbound queue overflowed, closed once the current command is done
+    slow_consumers: Vec<ConnId>,
+    shutting_down: bool,
+    // Set once shutdown begins; every session is closed when it passes
+    drain_deadline: Option<Instant>,
+    shutdown_waiters: Vec<oneshot::Sender<()>>,
 }
 
 impl ChatServer {
-    pub fn new() -> Self {
+    pub fn new(config: ChatServerConfig) -> Self {
         Self {
             sessions: HashMap::new(),
+            detached: HashMap::new(),
+            resume_tokens: HashMap::new(),
             users: HashMap::new(),
-            waiting_users: HashMap::new(),
+            waiting_users: WaitQueue::new(),
             groups: HashMap::new(),
+            recent_partners: HashMap::new(),
+            reports: ModerationQueue::default(),
+            bans: BanList::default(),
+            config,
+            metrics: ServerMetrics::default(),
+            slow_consumers: Vec::new(),
+            shutting_down: false,
+            drain_deadline: None,
+            shutdown_waiters: Vec::new(),
         }
     }
 
-    pub fn start() -> ChatServerHandle {
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let server = Self::new();
+    pub fn start(config: ChatServerConfig) -> ChatServerHandle {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(config.command_queue_capacity);
+        let outbound_capacity = config.outbound_queue_capacity;
 
-        // Spawn a task to run the server
+        // Spawn a task to run the server. If it panics, a fresh server takes over the same
+        // command queue: connections of the crashed instance lose their channels and close, and
+        // their clients reconnect. Bans and reports are handed over so a crash cannot lift them.
         tokio::spawn(async move {
-            server.run(cmd_rx).await.unwrap();
+            let mut server = Self::new(config.clone());
+            loop {
+                match AssertUnwindSafe(server.run(&mut cmd_rx)).catch_unwind().await {
+                    Ok(Ok(())) => break,
+                    Ok(Err(err)) => {
+                        log::error!("Chat server stopped: {}", err);
+                        break;
+                    }
+                    Err(_) => {
+                        let restarts = server.metrics.actor_restarts + 1;
+                        log::error!("Chat server panicked; restarting without its chats (restart {})", restarts);
+                        let mut restarted = Self::new(config.clone());
+                        restarted.bans = std::mem::take(&mut server.bans);
+                        restarted.reports = std::mem::take(&mut server.reports);
+                        restarted.metrics.actor_restarts = restarts;
+                        server = restarted;
+                    }
+                }
+            }
         });
 
-        ChatServerHandle { cmd_tx }
+        ChatServerHandle {
+            cmd_tx: CommandSender(cmd_tx),
+            outbound_capacity,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    fn generate_group_code(&self) -> String {
+    // A code no live group is using
+    fn generate_group_code(&self) -> RoomId {
         let mut rng = rand::thread_rng();
-        (0..6).map(|_| rng.gen_range(0..36).to_string().to_uppercase()).collect()
+        loop {
+            let code = self.config.group_code.generate(&mut rng);
+            if !self.groups.contains_key(&code) {
+                return code;
+            }
+        }
     }
 
-    async fn handle_disconnect(&mut self, conn: &ConnId) {
-        if let Some(user) = self.users.remove(conn) {
-            if user.room_type == "group" {
-                if let Some(group_id) = user.group_id {
-                    if let Some(group) = self.groups.get_mut(&group_id) {
-                        group.members.retain(|id| id != conn);
-                        group.usernames.retain(|name| name != &user.username);
-                        if group.members.is_empty() {
-                            self.groups.remove(&group_id);
-                        } else {
-                            for member_id in &group.members {
-                                if let Some(tx) = self.sessions.get(member_id) {
-                                    let event = ServerEvent {
-                                        event: "user_left_group".to_string(),
-                                        data: serde_json::json!(user.username),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-
-                                    let event = ServerEvent {
-                                        event: "group_members_update".to_string(),
-                                        data: serde_json::json!(group.usernames.clone()),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                }
-            } else {
-                if let Some(partner_id) = user.partner_id {
-                    if let Some(tx) = self.sessions.get(&partner_id) {
-                        let event = ServerEvent {
-                            event: "partner_disconnected".to_string(),
-                            data: serde_json::json!({}),
-                        };
-                        let _ = tx.send(serde_json::to_string(&event).unwrap());
-                    }
-                    if let Some(partner) = self.users.get_mut(&partner_id) {
-                        partner.partner_id = None;
-                    }
-                }
+    // Queue a message for a connection. Typing indicators are dropped once the queue is
+    // mostly full; anything else that does not fit marks the connection as a slow consumer.
+    // Returns whether the message was queued or buffered for the connection
+    fn deliver(&mut self, conn: &ConnId, msg: Msg, droppable: bool) -> bool {
+        let Some(session) = self.sessions.get(conn) else {
+            return self.buffer_for_detached(conn, msg, droppable);
+        };
+        if droppable && session.tx.capacity() * 4 < session.tx.max_capacity() {
+            self.metrics.typing_events_dropped += 1;
+            return false;
+        }
+        match session.tx.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.mark_slow_consumer(conn);
+                false
             }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
         }
-        for list in self.waiting_users.values_mut() {
-            list.retain(|id| id != conn);
+    }
+
+    // Hold a message for a user whose socket dropped until they resume
+    fn buffer_for_detached(&mut self, conn: &ConnId, msg: Msg, droppable: bool) -> bool {
+        let Some(detached) = self.detached.get_mut(conn) else {
+            return false;
+        };
+        if droppable {
+            return false;
+        }
+        if detached.buffer.len() < self.config.outbound_queue_capacity {
+            detached.buffer.push(msg);
+            true
+        } else {
+            self.mark_slow_consumer(conn);
+            false
         }
     }
 
-    async fn find_match(&mut self, conn: &ConnId) {
-        if let Some(user) = self.users.get(conn) {
-            let preference = &user.preference;
-            let match_pool: Vec<ConnId> = self.waiting_users.get(preference).cloned().unwrap_or_default()
-                .into_iter()
-                .filter(|id| {
-                    if let Some(potential_match) = self.users.get(id) {
-                        match preference.as_str() {
-                            "male" => potential_match.gender == "male",
-                            "female" => potential_match.gender == "female",
-                            _ => false,
-                        }
-                    } else {
-                        false
-                    }
-                })
-                .collect();
-            
-            if !match_pool.is_empty() {
-                let random_index = rand::random::<usize>() % match_pool.len();
-                let partner_id = match_pool[random_index].clone();
-                self.connect_users(conn, &partner_id).await;
-            } else {
-                self.waiting_users.entry(preference.clone()).or_insert_with(Vec::new).push(conn.to_string());
-                if let Some(tx) = self.sessions.get(conn) {
-                    let event = ServerEvent {
-                        event: "waiting_for_match".to_string(),
-                        data: serde_json::json!({}),
-                    };
-                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                }
-            }
+    fn mark_slow_consumer(&mut self, conn: &ConnId) {
+        if !self.slow_consumers.contains(conn) {
+            self.slow_consumers.push(conn.clone());
         }
     }
 
-    async fn connect_users(&mut self, user1_id: &ConnId, user2_id: &ConnId) {
-        if let Some(user1) = self.users.get_mut(user1_id) {
-            user1.partner_id = Some(user2_id.to_string());
+    // Send an event to a single connection, if it is still connected
+    fn send_event(&mut self, conn: &ConnId, event: &ServerMessage) -> bool {
+        match encode_event(event) {
+            Some(msg) => self.deliver(conn, msg, event.is_droppable()),
+            None => false,
         }
-        if let Some(user2) = self.users.get_mut(user2_id) {
-            user2.partner_id = Some(user1_id.to_string());
+    }
+
+    // Send an event to every member of a group, optionally skipping one of them.
+    // Returns whether there was at least one recipient and every one of them got the event.
+    fn broadcast_group(&mut self, group_id: &RoomId, except: Option<&ConnId>, event: &ServerMessage) -> bool {
+        let Some(members) = self.groups.get(group_id).map(|group| group.members.clone()) else {
+            return false;
+        };
+        let Some(msg) = encode_event(event) else {
+            return false;
+        };
+        let mut recipients = 0;
+        let mut delivered = 0;
+        for member_id in &members {
+            if Some(member_id) == except {
+                continue;
+            }
+            recipients += 1;
+            if self.deliver(member_id, msg.clone(), event.is_droppable()) {
+                delivered += 1;
+            }
         }
-        for list in self.waiting_users.values_mut() {
-            list.retain(|id| id != user1_id && id != user2_id);
+        recipients > 0 && delivered == recipients
+    }
+
+    // Close a connection on the server's initiative and clean up its chat state
+    async fn close_connection(&mut self, conn: &ConnId, reason: DisconnectReason) {
+        if let Some(mut session) = self.sessions.remove(conn) {
+            log::warn!("Closing connection {}: {:?}", conn, reason);
+            self.resume_tokens.remove(&session.resume_token);
+            if let Some(close_tx) = session.close_tx.take() {
+                let _ = close_tx.send(reason);
+            }
         }
-        if let Some(tx1) = self.sessions.get(user1_id) {
-            let event = ServerEvent {
-                event: "chat_started".to_string(),
-                data: serde_json::json!({}),
-            };
-            let _ = tx1.send(serde_json::to_string(&event).unwrap());
+        self.handle_disconnect(conn).await;
+    }
+
+    // A socket ended. Joined users are kept for the grace period so that they can resume.
+    async fn handle_socket_closed(&mut self, conn: &ConnId) {
+        let Some(session) = self.sessions.remove(conn) else {
+            return;
+        };
+        let grace = self.config.resume_grace_period;
+        if let Some(user) = self.users.get_mut(conn).filter(|_| !self.shutting_down && !grace.is_zero()) {
+            // Waiting users are matched again when they come back
+            user.waiting_since = None;
+            self.waiting_users.remove(conn);
+            self.detached.insert(conn.clone(), Detached {
+                resume_token: session.resume_token,
+                expires: Instant::now() + grace,
+                buffer: Vec::new(),
+            });
+        } else {
+            self.resume_tokens.remove(&session.resume_token);
+            self.handle_disconnect(conn).await;
         }
-        if let Some(tx2) = self.sessions.get(user2_id) {
-            let event = ServerEvent {
-                event: "chat_started".to_string(),
-                data: serde_json::json!({}),
-            };
-            let _ = tx2.send(serde_json::to_string(&event).unwrap());
+    }
+
+    // Give up on a detached user and tell their partner or group they left
+    async fn expire_detached(&mut self, conn: &ConnId) {
+        let removed = self.detached.remove(conn);
+        if let Some(detached) = removed {
+            log::info!("Resume grace period expired for {}", conn);
+            self.resume_tokens.remove(&detached.resume_token);
+            self.handle_disconnect(conn).await;
         }
     }
 
-    async fn create_new_group(&mut self, conn: &ConnId) {
-        let group_code = self.generate_group_code();
-        if let Some(user) = self.users.get_mut(conn) {
-            let group = Group {
-                code: group_code.clone(),
-                members: vec![conn.to_string()],
-                usernames: vec![user.username.clone()],
-            };
-            self.groups.insert(group_code.clone(), group);
-            user.group_id = Some(group_code.clone());
-            if let Some(tx) = self.sessions.get(conn) {
-                let event = ServerEvent {
-                    event: "chat_started".to_string(),
-                    data: serde_json::json!({ "groupCode": group_code.clone() }),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+    async fn expire_detached_sessions(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<ConnId> = self
+            .detached
+            .iter()
+            .filter(|(_, detached)| detached.expires <= now)
+            .map(|(conn, _)| conn.clone())
+            .collect();
+        for conn in expired {
+            self.expire_detached(&conn).await;
+        }
+    }
 
-                let event = ServerEvent {
-                    event: "group_members_update".to_string(),
-                    data: serde_json::json!(vec![user.username.clone()]),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+    // Move the user state of a dropped connection onto the socket that presented its token
+    async fn handle_resume(&mut self, conn: &ConnId, resume_token: &str) -> Result<ConnId, RequestError> {
+        if self.users.contains_key(conn) {
+            return Err(RequestError::new(ErrorCode::InvalidState, "resume must be sent before join_chat"));
+        }
+        let resumed = self
+            .resume_tokens
+            .get(resume_token)
+            .filter(|resumed| *resumed != conn && self.users.contains_key(*resumed))
+            .cloned()
+            .ok_or_else(|| RequestError::new(ErrorCode::ResumeFailed, "unknown or expired resume token"))?;
+
+        // The old socket may not have noticed it dropped yet
+        if let Some(mut old) = self.sessions.remove(&resumed) {
+            if let Some(close_tx) = old.close_tx.take() {
+                let _ = close_tx.send(DisconnectReason::Replaced);
             }
         }
+        let buffered = self
+            .detached
+            .remove(&resumed)
+            .map(|detached| detached.buffer)
+            .unwrap_or_default();
+
+        // Attach the new socket under the resumed id with a fresh token
+        let Some(mut session) = self.sessions.remove(conn) else {
+            return Err(RequestError::new(ErrorCode::InvalidState, "connection is closed"));
+        };
+        self.resume_tokens.remove(resume_token);
+        self.resume_tokens.remove(&session.resume_token);
+        session.resume_token = Uuid::new_v4().to_string();
+        self.resume_tokens.insert(session.resume_token.clone(), resumed.clone());
+        let event = ServerMessage::Resumed {
+            resume_token: session.resume_token.clone(),
+        };
+        self.sessions.insert(resumed.clone(), session);
+        log::info!("Connection {} resumed as {}", conn, resumed);
+
+        self.send_event(&resumed, &event);
+        for msg in buffered {
+            self.deliver(&resumed, msg, false);
+        }
+
+        let needs_match = self.users.get(&resumed).is_some_and(|user| {
+            user.room_type != "group" && user.partner_id.is_none() && user.waiting_since.is_none()
+        });
+        if needs_match {
+            self.find_match(&resumed).await;
+        }
+        Ok(resumed)
     }
 
-    async fn join_group_by_code(&mut self, conn: &ConnId, group_code: &str) {
-        if let Some(group) = self.groups.get_mut(group_code) {
-            if let Some(user) = self.users.get_mut(conn) {
-                group.members.push(conn.to_string());
-                group.usernames.push(user.username.clone());
-                user.group_id = Some(group_code.to_string());
-                for member_id in &group.members {
-                    if let Some(tx) = self.sessions.get(member_id) {
-                        let event = ServerEvent {
-                            event: "group_members_update".to_string(),
-                            data: serde_json::json!(group.usernames.clone()),
-                        };
-                        let _ = tx.send(serde_json::to_string(&event).unwrap());
-                        if member_id != conn {
-                            let event = ServerEvent {
-                                event: "user_joined_group".to_string(),
-                                data: serde_json::json!(user.username.clone()),
-                            };
-                            let _ = tx.send(serde_json::to_string(&event).unwrap());
-                        }
-                    }
-                }
-                if let Some(tx) = self.sessions.get(conn) {
-                    let event = ServerEvent {
-                        event: "chat_started".to_string(),
-                        data: serde_json::json!({ "groupCode": group_code.to_string() }),
-                    };
-                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                }
+    async fn evict_slow_consumers(&mut self) {
+        // Closing one connection notifies its partner or group, which may overflow another queue
+        loop {
+            let slow_consumers = std::mem::take(&mut self.slow_consumers);
+            if slow_consumers.is_empty() {
+                break;
             }
-        } else {
-            if let Some(tx) = self.sessions.get(conn) {
-                let event = ServerEvent {
-                    event: "group_not_found".to_string(),
-                    data: serde_json::json!({}),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+            for conn in slow_consumers {
+                if self.sessions.contains_key(&conn) {
+                    self.metrics.slow_consumer_disconnects += 1;
+                    self.close_connection(&conn, DisconnectReason::SlowConsumer).await;
+                } else {
+                    // A detached user whose resume buffer filled up
+                    self.expire_detached(&conn).await;
+                }
             }
         }
     }
 
-    async fn join_random_group(&mut self, conn: &ConnId) {
-        let group_code_option = {
-            let available_groups: Vec<&Group> = self.groups.values().filter(|g| !g.members.is_empty()).collect();
-            if available_groups.is_empty() {
-                None
-            } else {
-                let random_index = rand::random::<usize>() % available_groups.len();
-                Some(available_groups[random_index].code.clone())
-            }
+    // Warn every client and start the drain period
+    fn begin_shutdown(&mut self) {
+        if self.shutting_down {
+            return;
+        }
+        self.shutting_down = true;
+        let drain_timeout = self.config.shutdown_drain_timeout;
+        log::info!("Shutting down; draining {} connections for {:?}", self.sessions.len(), drain_timeout);
+        self.drain_deadline = Some(Instant::now() + drain_timeout);
+        let event = ServerMessage::ServerShuttingDown {
+            reconnect_after_ms: duration_ms(self.config.shutdown_reconnect_after),
+            drain_timeout_ms: duration_ms(drain_timeout),
         };
-        
-        match group_code_option {
-            Some(code) => self.join_group_by_code(conn, &code).await,
-            None => self.create_new_group(conn).await,
+        let conns: Vec<ConnId> = self.sessions.keys().cloned().collect();
+        for conn in &conns {
+            self.send_event(conn, &event);
         }
     }
 
-    async fn run(mut self, mut cmd_rx: mpsc::UnboundedReceiver<Command>) -> Result<(), Box<dyn std::error::Error>> {
-        while let Some(cmd) = cmd_rx.recv().await {
-            match cmd {
-                Command::Connect { conn_tx, res_tx } => {
-                    let conn_id = Uuid::new_v4().to_string();
-                    self.sessions.insert(conn_id.clone(), conn_tx);
-                    let _ = res_tx.send(conn_id);
-                }
-                Command::Disconnect { conn } => {
-                    self.handle_disconnect(&conn).await;
-                }
-                Command::JoinChat { conn, profile, res_tx } => {
-                    let user = User {
-                        id: conn.clone(),
-                        user_id: profile.user_id.clone(),
-                        username: if profile.username.is_empty() { format!("User-{}", profile.user_id[..5].to_string()) } else { profile.username.clone() },
-                        gender: profile.gender.clone(),
-                        preference: profile.preference.clone(),
-                        room_type: profile.room_type.clone(),
-                        partner_id: None,
-                        group_id: None,
-                    };
-                    self.users.insert(conn.clone(), user);
-                    if profile.room_type == "group" {
-                        let join_method = profile.group_join_method.unwrap_or("random".to_string());
-                        if join_method == "create" {
-                            self.create_new_group(&conn).await;
-                        } else if join_method == "join" && profile.group_code.is_some() {
-                            self.join_group_by_code(&conn, &profile.group_code.unwrap()).await;
-                        } else {
-                            self.join_random_group(&conn).await;
-                        }
-                    } else {
-                        self.find_match(&conn).await;
-                    }
-                    let _ = res_tx.send(());
-                }
-                Command::SendMessage { conn, message, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "receive_message".to_string(),
-                                                    data: serde_json::json!({
-                                                        "message": message.clone(),
-                                                        "sender": user.username.clone()
-                                                    }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "receive_message".to_string(),
-                                        data: serde_json::json!({
-                                            "message": message.clone(),
-                                            "sender": user.username.clone()
-                                        }),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
-                }
-                Command::TypingStart { conn, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "typing_started".to_string(),
-                                                    data: serde_json::json!({ "username": user.username.clone() }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "typing_started".to_string(),
-                                        data: serde_json::json!({}),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
-                }
-                Command::TypingStop { conn, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "typing_stopped".to_string(),
-                                                    data: serde_json::json!({ "username": user.username.clone() }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "typing_stopped".to_string(),
-                                        data: serde_json::json!({}),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
-                }
-                Command::DisconnectChat { conn, res_tx } => {
-                    self.handle_disconnect(&conn).await;
-                    let _ = res_tx.send(());
-                }
+    // Close every remaining session once the drain period is over
+    fn finish_shutdown(&mut self) {
+        self.drain_deadline = None;
+        for (_, mut session) in self.sessions.drain() {
+            if let Some(close_tx) = session.close_tx.take() {
+                let _ = close_tx.send(DisconnectReason::ServerShutdown);
             }
         }
-        Ok(())
+        self.detached.clear();
+        self.resume_tokens.clear();
+        self.users.clear();
+        self.groups.clear();
+        self.waiting_users = WaitQueue::new();
+        for waiter in self.shutdown_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        log::info!("Shutdown complete; all connections closed");
     }
-}
 
-// Handle and command sender for chat server
-#[derive(Debug, Clone)]
-pub struct ChatServerHandle {
-    cmd_tx: mpsc::UnboundedSender<Command>,
-}
+    fn render_metrics(&self, cmd_rx: &mpsc::Receiver<Queued>) -> String {
+        let depths = self.sessions.values().map(|session| session.tx.max_capacity() - session.tx.capacity());
+        let (total, max) = depths.fold((0, 0), |(total, max), depth| (total + depth, max.max(depth)));
+        let queues = QueueSnapshot {
+            command_queue_depth: cmd_rx.len(),
+            outbound_queue_depth_total: total,
+            outbound_queue_depth_max: max,
+            outbound_queue_capacity: self.config.outbound_queue_capacity,
+        };
 
-impl ChatServerHandle {
-    // Register client message sender and obtain connection ID
-    pub async fn connect(&self, conn_tx: mpsc::UnboundedSender<Msg>) -> ConnId {
-        let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::Connect { conn_tx, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap()
+        let mut waiting_by_preference = BTreeMap::new();
+        for (_, preference, bucket) in self.waiting_users.buckets() {
+            let label = if METRIC_PREFERENCES.contains(&preference) { preference } else { "other" };
+            *waiting_by_preference.entry(label.to_string()).or_insert(0) += bucket.len();
+        }
+        let state = StateSnapshot {
+            connections: self.sessions.len(),
+            detached_sessions: self.detached.len(),
+            joined_users: self.users.len(),
+            waiting_by_preference,
+            pairs: self.users.values().filter(|user| user.partner_id.is_some()).count() / 2,
+            group_sizes: self.groups.values().map(|group| group.members.len()).collect(),
+        };
+        self.metrics.render(&queues, &state)
     }
 
-    // Unregister message sender and broadcast disconnection message to current room
-    pub fn disconnect(&self, conn: ConnId) {
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx.send(Command::Disconnect { conn }).unwrap();
+    // Count a client event that was relayed to other users
+    fn count_relayed(&mut self, event: &'static str, result: &Result<(), RequestError>) {
+        if result.is_ok() {
+            *self.metrics.messages_relayed.entry(event).or_insert(0) += 1;
+        }
     }
 
-    // Join chat with a user profile
-    pub async fn join_chat(&self, conn: ConnId, profile: UserProfile) {
-        let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::JoinChat { conn, profile, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+    // Connected and detached sessions ordered by connection ID, for the admin API
+    fn list_sessions(&self, offset: usize, limit: usize) -> Vec<SessionInfo> {
+        let detached = &self.detached;
+        let mut conns: Vec<&ConnId> = self.sessions.keys().chain(detached.keys()).collect();
+        conns.sort();
+        conns
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|conn| {
+                let session = self.sessions.get(conn);
+                let user = self.users.get(conn);
+                let state = match (session, user) {
+                    (None, _) => SessionState::Detached,
+                    (Some(_), None) => SessionState::Connected,
+                    (Some(_), Some(user)) if user.group_id.is_some() => SessionState::InGroup,
+                    (Some(_), Some(user)) if user.partner_id.is_some() => SessionState::Paired,
+                    (Some(_), Some(user)) if user.waiting_since.is_some() => SessionState::Waiting,
+                    (Some(_), Some(_)) => SessionState::Idle,
+                };
+                SessionInfo {
+                    conn_id: conn.clone(),
+                    state,
+                    ip: session.and_then(|session| session.client.ip).map(|ip| ip.to_string()),
+                    connected_secs: session.map(|session| session.connected_at.elapsed().as_secs()),
+                    user: user.map(|user| UserInfo {
+                        user_id: user.user_id.clone(),
+                        username: user.username.clone(),
+                        gender: user.gender.clone(),
+                        preference: user.preference.clone(),
+                        room_type: user.room_type.clone(),
+                        partner: user.partner_id.clone(),
+                        group_code: user.group_id.clone(),
+                    }),
+                }
+            })
+            .collect()
     }
 
-    // Send a message
-    pub async fn send_message(&self, conn: ConnId, message: EncryptedMessage, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::SendMessage { conn, message, is_group_chat, group_code, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+    fn list_waiting(&self) -> Vec<WaitingBucket> {
+        let now = Instant::now();
+        self.waiting_users
+            .buckets()
+            .map(|(gender, preference, bucket)| WaitingBucket {
+                gender: gender.to_string(),
+                preference: preference.to_string(),
+                users: bucket
+                    .iter()
+                    .filter_map(|conn| self.users.get(conn))
+                    .map(|user| WaitingUser {
+                        conn_id: user.id.clone(),
+                        username: user.username.clone(),
+                        waiting_secs: user.waiting_since.map_or(0, |since| now.duration_since(since).as_secs()),
+                    })
+                    .collect(),
+            })
+            .collect()
     }
 
-    // Start typing
-    pub async fn typing_start(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) {
+    // Each 1:1 pair once, ordered by the lower connection ID
+    fn list_pairs(&self, offset: usize, limit: usize) -> Vec<PairInfo> {
+        let mut pairs: Vec<(&User, &User)> = self
+            .users
+            .values()
+            .filter_map(|user| {
+                let partner = self.users.get(user.partner_id.as_ref()?)?;
+                (user.id < partner.id).then_some((user, partner))
+            })
+            .collect();
+        pairs.sort_by(|a, b| a.0.id.cmp(&b.0.id));
+        pairs
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(a, b)| PairInfo {
+                conn_ids: [a.id.clone(), b.id.clone()],
+                usernames: [a.username.clone(), b.username.clone()],
+            })
+            .collect()
+    }
+
+    fn list_groups(&self, offset: usize, limit: usize) -> Vec<GroupInfo> {
+        let mut groups: Vec<&Group> = self.groups.values().collect();
+        groups.sort_by(|a, b| a.code.cmp(&b.code));
+        groups
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|group| GroupInfo {
+                code: group.code.clone(),
+                member_count: group.members.len(),
+                max_size: group.max_size,
+                visibility: group.visibility,
+                owner: self.username_of(&group.owner),
+                usernames: group.usernames.clone(),
+            })
+            .collect()
+    }
+
+    // A page of public groups, ordered by code so that pages stay stable as groups fill up
+    fn group_directory(&self, query: &ListGroupsData) -> GroupDirectoryPage {
+        let language = normalize_language(query.language.as_deref());
+        let mut groups: Vec<&Group> = self
+            .groups
+            .values()
+            .filter(|group| group.visibility == GroupVisibility::Public && !group.members.is_empty())
+            .filter(|group| language.is_none() || group.language == language)
+            .collect();
+        groups.sort_by(|a, b| a.code.cmp(&b.code));
+        let limit = query.limit.unwrap_or(DEFAULT_GROUP_PAGE_SIZE).min(MAX_GROUP_PAGE_SIZE);
+        GroupDirectoryPage {
+            total: groups.len(),
+            groups: groups
+                .into_iter()
+                .skip(query.offset)
+                .take(limit)
+                .map(|group| PublicGroup {
+                    code: group.code.clone(),
+                    member_count: group.members.len(),
+                    max_size: group.max_size,
+                    title: group.title.clone(),
+                    language: group.language.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    // Close a connection on an administrator's request
+    async fn kick(&mut self, conn: &ConnId) -> bool {
+        if self.sessions.contains_key(conn) {
+            self.close_connection(conn, DisconnectReason::Kicked).await;
+            true
+        } else if self.detached.contains_key(conn) {
+            self.expire_detached(conn).await;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Remove a group, leaving its members joined but outside any group
+    fn dissolve_group(&mut self, code: &str) -> bool {
+        let Some(group) = self.groups.remove(code) else {
+            return false;
+        };
+        log::info!("Dissolving group {}", code);
+        for member in &group.members {
+            if let Some(user) = self.users.get_mut(member) {
+                user.group_id = None;
+            }
+            self.send_event(member, &ServerMessage::GroupDissolved {});
+        }
+        true
+    }
+
+    // Relay an event from a user to their group or their 1:1 partner. Group events only go to
+    // the sender's own group; a `group_code` naming any other group is rejected.
+    // Returns whether the event reached every recipient, and false if there were none
+    fn relay_from(&mut self, conn: &ConnId, is_group_chat: bool, group_code: Option<String>, event: &ServerMessage) -> Result<bool, RequestError> {
+        let user = self.joined_user(conn)?;
+        if is_group_chat {
+            let group_id = self.authorized_group(conn, user, group_code.as_deref())?.clone();
+            Ok(self.broadcast_group(&group_id, Some(conn), event))
+        } else if let Some(partner_id) = user.partner_id.clone() {
+            Ok(self.send_event(&partner_id, event))
+        } else {
+            Ok(false)
+        }
+    }
+
+    // Relay a message and acknowledge it to the sender if it carried an id
+    fn handle_send_message(
+        &mut self,
+        conn: &ConnId,
+        message: EncryptedMessage,
+        is_group_chat: bool,
+        group_code: Option<String>,
+        message_id: Option<String>,
+    ) -> Result<(), RequestError> {
+        if let Some(message_id) = &message_id {
+            validate_message_id(message_id)?;
+        }
+        let user = self.joined_user(conn)?;
+        if is_group_chat {
+            self.check_not_muted(user)?;
+        }
+        let event = ServerMessage::ReceiveMessage {
+            message,
+            sender: user.username.clone(),
+            message_id: message_id.clone(),
+        };
+        let delivered = self.relay_from(conn, is_group_chat, group_code, &event)?;
+        if let Some(message_id) = message_id {
+            let status = if delivered {
+                DeliveryStatus::Delivered
+            } else {
+                DeliveryStatus::NoRecipient
+            };
+            self.send_event(conn, &ServerMessage::MessageAck {
+                message_id,
+                status,
+                retry_after_ms: None,
+            });
+        }
+        Ok(())
+    }
+
+    // Relay a public key to the partner, or record it for the group and announce it to the other members
+    fn handle_key_exchange(&mut self, conn: &ConnId, data: KeyExchangeData) -> Result<(), RequestError> {
+        if data.public_key.is_empty() || data.public_key.len() > MAX_PUBLIC_KEY_LEN {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("public_key must be 1 to {} bytes", MAX_PUBLIC_KEY_LEN),
+            ));
+        }
+        if data.fingerprint.len() > MAX_FINGERPRINT_LEN {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("fingerprint must be at most {} bytes", MAX_FINGERPRINT_LEN),
+            ));
+        }
+        let user = self.joined_user(conn)?;
+        let key = MemberKey {
+            username: user.username.clone(),
+            public_key: data.public_key,
+            fingerprint: data.fingerprint,
+        };
+        if !data.is_group_chat {
+            if let Some(partner_id) = user.partner_id.clone() {
+                self.send_event(&partner_id, &ServerMessage::KeyExchange(key));
+            }
+            return Ok(());
+        }
+
+        let group_id = self.authorized_group(conn, user, data.group_code.as_deref())?.clone();
+        let user_id = user.user_id.clone();
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            return Ok(());
+        };
+        let previous = group.fingerprints.insert(user_id, key.fingerprint.clone());
+        group.keys.insert(conn.clone(), key.clone());
+        let event = if previous.is_some_and(|fingerprint| fingerprint != key.fingerprint) {
+            ServerMessage::KeyChanged(key)
+        } else {
+            ServerMessage::KeyExchange(key)
+        };
+        self.broadcast_group(&group_id, Some(conn), &event);
+        Ok(())
+    }
+
+    // Relay WebRTC signaling to the partner, or to one member of a small group
+    fn handle_signal(&mut self, conn: &ConnId, route: SignalRoute, signal: Signal) -> Result<(), RequestError> {
+        signal.validate()?;
+        let user = self.joined_user(conn)?;
+        let target = self.signal_target(conn, user, route)?;
+        let event = signal.into_event(user.username.clone());
+        self.send_event(&target, &event);
+        Ok(())
+    }
+
+    // The connection a signaling event is for, which must be the sender's partner or a fellow group member
+    fn signal_target(&self, conn: &ConnId, user: &User, route: SignalRoute) -> Result<ConnId, RequestError> {
+        let invalid_target = || RequestError::new(ErrorCode::InvalidTarget, "target is not in this chat");
+        if !route.is_group_chat {
+            let partner_id = user.partner_id.as_ref().ok_or_else(|| {
+                RequestError::new(ErrorCode::InvalidState, "no partner to call")
+            })?;
+            let partner = self.users.get(partner_id).ok_or_else(invalid_target)?;
+            if route.target.is_some_and(|target| target != partner.username) {
+                return Err(invalid_target());
+            }
+            return Ok(partner_id.clone());
+        }
+
+        let group_id = self.authorized_group(conn, user, route.group_code.as_deref())?;
+        let Some(group) = self.groups.get(group_id) else {
+            return Err(invalid_target());
+        };
+        if group.members.len() > self.config.webrtc_max_group_size {
+            return Err(RequestError::new(
+                ErrorCode::InvalidState,
+                format!("calls are limited to groups of {}", self.config.webrtc_max_group_size),
+            ));
+        }
+        let target = route.target.ok_or_else(|| {
+            RequestError::new(ErrorCode::InvalidPayload, "target is required in group chats")
+        })?;
+        self.group_member_named(conn, group, &target).cloned()
+    }
+
+    // The other member of a group with the given username
+    fn group_member_named<'a>(&self, conn: &ConnId, group: &'a Group, username: &str) -> Result<&'a ConnId, RequestError> {
+        let mut matches = group.members.iter().filter(|member| {
+            *member != conn && self.users.get(*member).is_some_and(|member| member.username == username)
+        });
+        match (matches.next(), matches.next()) {
+            (Some(member), None) => Ok(member),
+            (Some(_), Some(_)) => Err(RequestError::new(ErrorCode::InvalidTarget, "several members share that username")),
+            (None, _) => Err(RequestError::new(ErrorCode::InvalidTarget, "target is not in this chat")),
+        }
+    }
+
+    // The sender's group and the member a moderation command names. The sender needs at least
+    // `required` rank and must outrank the member.
+    fn moderation_target(&self, conn: &ConnId, target: &str, required: GroupRole) -> Result<(RoomId, ConnId), RequestError> {
+        let user = self.joined_user(conn)?;
+        let group = user
+            .group_id
+            .as_ref()
+            .and_then(|group_id| self.groups.get(group_id))
+            .ok_or_else(|| RequestError::new(ErrorCode::InvalidState, "not in a group"))?;
+        let role = group.role(conn);
+        if role < required {
+            return Err(RequestError::new(ErrorCode::Forbidden, "your group role does not allow this"));
+        }
+        let member = self.group_member_named(conn, group, target)?;
+        if group.role(member) >= role {
+            return Err(RequestError::new(ErrorCode::Forbidden, "cannot moderate a member of equal or higher rank"));
+        }
+        Ok((group.code.clone(), member.clone()))
+    }
+
+    fn username_of(&self, conn: &ConnId) -> String {
+        self.users.get(conn).map(|user| user.username.clone()).unwrap_or_default()
+    }
+
+    // Remove a member from the sender's group, leaving them joined but outside any group
+    fn handle_kick_member(&mut self, conn: &ConnId, target: &str) -> Result<(), RequestError> {
+        let (group_id, member) = self.moderation_target(conn, target, GroupRole::Moderator)?;
+        let username = self.username_of(&member);
+        let event = ServerMessage::MemberKicked {
+            username: username.clone(),
+            by: self.username_of(conn),
+        };
+        log::info!("{} kicked {} from group {}", conn, member, group_id);
+        self.send_event(&member, &event);
+        if let (Some(group), Some(user)) = (self.groups.get_mut(&group_id), self.users.get(&member)) {
+            group.kicked.insert(user.user_id.clone());
+        }
+        self.remove_group_member(&group_id, &member, &username, event);
+        if let Some(user) = self.users.get_mut(&member) {
+            user.group_id = None;
+        }
+        Ok(())
+    }
+
+    // Mute a member of the sender's group, or lift their mute when the duration is zero
+    fn handle_mute_member(&mut self, conn: &ConnId, data: MuteMemberData) -> Result<(), RequestError> {
+        let duration = Duration::from_secs(data.duration_secs);
+        if duration > MAX_MUTE_DURATION {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("duration_secs must be at most {}", MAX_MUTE_DURATION.as_secs()),
+            ));
+        }
+        let (group_id, member) = self.moderation_target(conn, &data.target, GroupRole::Moderator)?;
+        let event = ServerMessage::MemberMuted {
+            username: self.username_of(&member),
+            by: self.username_of(conn),
+            duration_ms: duration_ms(duration),
+        };
+        let user_id = self.users.get(&member).map(|user| user.user_id.clone()).unwrap_or_default();
+        if let Some(group) = self.groups.get_mut(&group_id) {
+            let now = Instant::now();
+            group.muted.retain(|_, until| *until > now);
+            if duration.is_zero() {
+                group.muted.remove(&user_id);
+            } else {
+                group.muted.insert(user_id, now + duration);
+            }
+        }
+        self.broadcast_group(&group_id, None, &event);
+        Ok(())
+    }
+
+    // Make a member of the sender's group a moderator
+    fn handle_promote_member(&mut self, conn: &ConnId, target: &str) -> Result<(), RequestError> {
+        let (group_id, member) = self.moderation_target(conn, target, GroupRole::Owner)?;
+        let event = ServerMessage::MemberPromoted {
+            username: self.username_of(&member),
+            by: self.username_of(conn),
+        };
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            return Ok(());
+        };
+        if group.role(&member) == GroupRole::Moderator {
+            return Err(RequestError::new(ErrorCode::InvalidState, "already a moderator"));
+        }
+        group.moderators.push(member);
+        self.broadcast_group(&group_id, None, &event);
+        if let Some(roles) = self.group_roles(&group_id) {
+            self.broadcast_group(&group_id, None, &roles);
+        }
+        Ok(())
+    }
+
+    // Reject group messages and typing events from a muted member
+    fn check_not_muted(&self, user: &User) -> Result<(), RequestError> {
+        let remaining = user
+            .group_id
+            .as_ref()
+            .and_then(|group_id| self.groups.get(group_id))
+            .and_then(|group| group.mute_remaining(&user.user_id, Instant::now()));
+        match remaining {
+            Some(remaining) => Err(RequestError::new(
+                ErrorCode::Muted,
+                format!("muted for another {} seconds", remaining.as_secs().max(1)),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // File a report against the partner, the last partner, or a group member
+    fn handle_report(&mut self, conn: &ConnId, data: ReportUserData) -> Result<(), RequestError> {
+        if data.messages.len() > MAX_REPORTED_MESSAGES {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("at most {} messages may be attached", MAX_REPORTED_MESSAGES),
+            ));
+        }
+        if data.details.as_ref().is_some_and(|details| details.len() > MAX_REPORT_DETAILS_LEN) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("details must be at most {} bytes", MAX_REPORT_DETAILS_LEN),
+            ));
+        }
+        if data
+            .messages
+            .iter()
+            .any(|message| message.encrypted.len() > MAX_REPORTED_CIPHERTEXT_LEN || message.nonce.len() > MAX_REPORTED_NONCE_LEN)
+        {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!(
+                    "attached messages must be at most {} bytes with a nonce of at most {} bytes",
+                    MAX_REPORTED_CIPHERTEXT_LEN, MAX_REPORTED_NONCE_LEN
+                ),
+            ));
+        }
+        let user = self.joined_user(conn)?;
+        let reporter_ip = self.sessions.get(conn).and_then(|session| session.client.ip);
+        if self.reports.open_reports_by(&user.user_id, reporter_ip) >= MAX_OPEN_REPORTS_PER_REPORTER {
+            return Err(RequestError::new(
+                ErrorCode::TooManyReports,
+                format!("at most {} reports may be waiting for review", MAX_OPEN_REPORTS_PER_REPORTER),
+            ));
+        }
+        let (reported_user_id, reported_username) = if user.room_type == "group" {
+            let group_id = self.authorized_group(conn, user, None)?;
+            let group = self.groups.get(group_id).ok_or_else(RequestError::not_joined)?;
+            let target = data.target.as_deref().ok_or_else(|| {
+                RequestError::new(ErrorCode::InvalidPayload, "target is required in group chats")
+            })?;
+            let member = &self.users[self.group_member_named(conn, group, target)?];
+            (member.user_id.clone(), member.username.clone())
+        } else {
+            let partner = user.partner_id.as_ref().and_then(|partner_id| self.users.get(partner_id));
+            let reported = match partner {
+                Some(partner) => Some((partner.user_id.clone(), partner.username.clone())),
+                None => user.last_partner.clone(),
+            };
+            reported
+                .filter(|(_, username)| data.target.as_ref().is_none_or(|target| target == username))
+                .ok_or_else(|| RequestError::new(ErrorCode::InvalidTarget, "no such partner to report"))?
+        };
+
+        let report = NewReport {
+            reporter_user_id: user.user_id.clone(),
+            reporter_username: user.username.clone(),
+            reporter_ip,
+            reported_user_id,
+            reported_username,
+            reason: data.reason,
+            details: data.details,
+            messages: data.messages,
+        };
+        if report.size() > MAX_REPORT_BYTES {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("details and attached messages must be at most {} bytes together", MAX_REPORT_BYTES),
+            ));
+        }
+        let report_id = self.reports.submit(report);
+        log::info!("Report {} filed by {} ({:?})", report_id, conn, data.reason);
+        self.send_event(conn, &ServerMessage::ReportReceived { report_id });
+        Ok(())
+    }
+
+    // STUN/TURN servers to include in `chat_started`
+    fn ice_servers(&self) -> Option<Vec<IceServer>> {
+        (!self.config.ice_servers.is_empty()).then(|| self.config.ice_servers.clone())
+    }
+
+    // Add a ban and close every connection it covers
+    async fn handle_add_ban(&mut self, target: BanTarget, duration: Option<Duration>, reason: Option<String>) -> Ban {
+        let ban = self.bans.add(target, duration, reason);
+        log::info!("Ban {} added: {:?}", ban.id, ban.target);
+
+        let banned_conns: Vec<ConnId> = self
+            .users
+            .values()
+            .filter(|user| {
+                let ip = self.sessions.get(&user.id).and_then(|session| session.client.ip);
+                ban.matches(Some(&user.user_id), ip, user.device_fingerprint.as_deref())
+            })
+            .map(|user| user.id.clone())
+            .chain(
+                self.sessions
+                    .iter()
+                    .filter(|(_, session)| ban.matches(None, session.client.ip, session.client.device_fingerprint.as_deref()))
+                    .map(|(conn, _)| conn.clone()),
+            )
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        for conn in banned_conns {
+            self.close_banned(&conn, &ban).await;
+        }
+        ban
+    }
+
+    // Tell a connection it is banned and close it, or drop its state if its socket is already gone
+    async fn close_banned(&mut self, conn: &ConnId, ban: &Ban) {
+        if self.sessions.contains_key(conn) {
+            self.send_event(conn, &ban.event());
+            self.close_connection(conn, DisconnectReason::Banned).await;
+        } else {
+            self.expire_detached(conn).await;
+        }
+    }
+
+    // Pass a recipient's receipt back to their 1:1 partner
+    fn handle_message_delivered(&mut self, conn: &ConnId, message_id: String) -> Result<(), RequestError> {
+        validate_message_id(&message_id)?;
+        let user = self.joined_user(conn)?;
+        if user.room_type == "group" {
+            return Err(RequestError::new(ErrorCode::InvalidState, "delivery receipts are only supported in 1:1 chats"));
+        }
+        if let Some(partner_id) = user.partner_id.clone() {
+            self.send_event(&partner_id, &ServerMessage::MessageDelivered { message_id });
+        }
+        Ok(())
+    }
+
+    // The group a user may act in, checked against the group's member list
+    fn authorized_group<'a>(&self, conn: &ConnId, user: &'a User, group_code: Option<&str>) -> Result<&'a RoomId, RequestError> {
+        let not_member = || {
+            log::warn!("Rejected group action from {} for group {:?}", conn, group_code);
+            RequestError::new(ErrorCode::NotGroupMember, "not a member of that group")
+        };
+        let Some(group_id) = user.group_id.as_ref() else {
+            return Err(match group_code {
+                Some(_) => not_member(),
+                None => RequestError::new(ErrorCode::InvalidState, "not in a group chat"),
+            });
+        };
+        let is_member = self.groups.get(group_id).is_some_and(|group| group.members.contains(conn));
+        if group_code.is_some_and(|code| code != group_id) || !is_member {
+            return Err(not_member());
+        }
+        Ok(group_id)
+    }
+
+    fn joined_user(&self, conn: &ConnId) -> Result<&User, RequestError> {
+        self.users.get(conn).ok_or_else(RequestError::not_joined)
+    }
+
+    // Take a member out of a group, handing ownership on if they owned it. The group is deleted
+    // once empty; otherwise the remaining members get `event` and the new member list.
+    fn remove_group_member(&mut self, group_id: &RoomId, conn: &ConnId, username: &str, event: ServerMessage) {
+        let Some(group) = self.groups.get_mut(group_id) else {
+            return;
+        };
+        group.members.retain(|id| id != conn);
+        group.keys.remove(conn);
+        group.usernames.retain(|name| name != username);
+        group.moderators.retain(|id| id != conn);
+        if group.members.is_empty() {
+            self.groups.remove(group_id);
+            return;
+        }
+        // Moderators take over in order of promotion, then the longest-standing member
+        let new_owner = (&group.owner == conn).then(|| {
+            let owner = group.moderators.first().unwrap_or(&group.members[0]).clone();
+            group.moderators.retain(|id| id != &owner);
+            group.owner = owner.clone();
+            owner
+        });
+        let usernames = group.usernames.clone();
+        self.broadcast_group(group_id, None, &event);
+        self.broadcast_group(group_id, None, &ServerMessage::GroupMembersUpdate(usernames));
+        if let Some(owner) = new_owner {
+            let username = self.users.get(&owner).map(|user| user.username.clone()).unwrap_or_default();
+            log::info!("Ownership of group {} passed to {}", group_id, owner);
+            self.broadcast_group(group_id, None, &ServerMessage::GroupOwnerChanged { username });
+            if let Some(roles) = self.group_roles(group_id) {
+                self.broadcast_group(group_id, None, &roles);
+            }
+        }
+    }
+
+    // The `group_roles` event describing a group's owner and moderators
+    fn group_roles(&self, group_id: &RoomId) -> Option<ServerMessage> {
+        let group = self.groups.get(group_id)?;
+        let username = |conn: &ConnId| self.users.get(conn).map(|user| user.username.clone());
+        Some(ServerMessage::GroupRoles {
+            owner: username(&group.owner).unwrap_or_default(),
+            moderators: group.moderators.iter().filter_map(username).collect(),
+        })
+    }
+
+    async fn handle_disconnect(&mut self, conn: &ConnId) {
+        if let Some(user) = self.users.remove(conn) {
+            if user.room_type == "group" {
+                if let Some(group_id) = user.group_id {
+                    let event = ServerMessage::UserLeftGroup(user.username.clone());
+                    self.remove_group_member(&group_id, conn, &user.username, event);
+                }
+            } else {
+                if let Some(partner_id) = user.partner_id {
+                    self.send_event(&partner_id, &ServerMessage::PartnerDisconnected {});
+                    if let Some(partner) = self.users.get_mut(&partner_id) {
+                        partner.partner_id = None;
+                    }
+                }
+            }
+        }
+        self.waiting_users.remove(conn);
+    }
+
+    // A waiting user without interests, or one who has waited past the timeout, accepts any partner
+    fn open_to_random(&self, user: &User, now: Instant) -> bool {
+        user.interests.is_empty()
+            || user
+                .waiting_since
+                .is_some_and(|since| now.duration_since(since) >= self.config.interest_match_timeout)
+    }
+
+    // Pick the waiting partner with the largest interest overlap, falling back to a random
+    // partner only when both sides are open to random matching
+    fn best_match(&self, conn: &ConnId) -> Option<(ConnId, Vec<String>)> {
+        let user = self.users.get(conn)?;
+        let now = Instant::now();
+        let user_open = self.open_to_random(user, now);
+
+        let mut best: Vec<(ConnId, Vec<String>)> = Vec::new();
+        let mut random_pool: Vec<ConnId> = Vec::new();
+        for id in self.waiting_users.candidates(&user.gender, &user.preference) {
+            if id == conn {
+                continue;
+            }
+            let Some(potential_match) = self.users.get(id) else {
+                continue;
+            };
+            if self.recently_paired(user, potential_match, now) {
+                continue;
+            }
+
+            let shared = shared_interests(&user.interests, &potential_match.interests);
+            if !shared.is_empty() {
+                let best_len = best.first().map_or(0, |(_, tags)| tags.len());
+                if shared.len() > best_len {
+                    best.clear();
+                }
+                if shared.len() >= best_len {
+                    best.push((id.clone(), shared));
+                }
+            } else if user_open && self.open_to_random(potential_match, now) {
+                random_pool.push(id.clone());
+            }
+        }
+
+        if !best.is_empty() {
+            let random_index = rand::random::<usize>() % best.len();
+            Some(best.swap_remove(random_index))
+        } else if !random_pool.is_empty() {
+            let random_index = rand::random::<usize>() % random_pool.len();
+            Some((random_pool.swap_remove(random_index), Vec::new()))
+        } else {
+            None
+        }
+    }
+
+    // Whether the two users skipped each other within the cooldown
+    fn recently_paired(&self, a: &User, b: &User, now: Instant) -> bool {
+        self.recent_partners
+            .get(&partner_pair(&a.user_id, &b.user_id))
+            .is_some_and(|at| now.duration_since(*at) < self.config.recent_partner_cooldown)
+    }
+
+    fn remember_partners(&mut self, conn: &ConnId, partner: &ConnId) {
+        let (Some(user), Some(partner)) = (self.users.get(conn), self.users.get(partner)) else {
+            return;
+        };
+        let pair = partner_pair(&user.user_id, &partner.user_id);
+        self.recent_partners.insert(pair, Instant::now());
+    }
+
+    // Forget skipped pairs whose cooldown has passed
+    fn expire_recent_partners(&mut self) {
+        let now = Instant::now();
+        let cooldown = self.config.recent_partner_cooldown;
+        self.recent_partners.retain(|_, at| now.duration_since(*at) < cooldown);
+    }
+
+    // End the current 1:1 pairing and look for new partners for both users
+    async fn handle_next(&mut self, conn: &ConnId) -> Result<(), RequestError> {
+        let user = self.users.get_mut(conn).ok_or_else(RequestError::not_joined)?;
+        if user.room_type == "group" {
+            return Err(RequestError::new(ErrorCode::InvalidState, "next is only available in 1:1 chats"));
+        }
+        let partner_id = user.partner_id.take();
+        let requeue = self.config.requeue_on_next;
+
+        if let Some(partner_id) = &partner_id {
+            self.remember_partners(conn, partner_id);
+            if let Some(partner) = self.users.get_mut(partner_id) {
+                partner.partner_id = None;
+            }
+            self.send_event(partner_id, &ServerMessage::PartnerSkipped { requeued: requeue });
+        }
+
+        // An idle user sending `next` always wants a new partner
+        let already_waiting = self.users.get(conn).is_some_and(|user| user.waiting_since.is_some());
+        if (requeue || partner_id.is_none()) && !already_waiting {
+            self.find_match(conn).await;
+        }
+        if requeue {
+            if let Some(partner_id) = &partner_id {
+                self.find_match(partner_id).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn find_match(&mut self, conn: &ConnId) {
+        if let Some((partner_id, shared)) = self.best_match(conn) {
+            self.connect_users(conn, &partner_id, shared).await;
+        } else if let Some(user) = self.users.get_mut(conn) {
+            user.waiting_since = Some(Instant::now());
+            self.waiting_users.push(conn.to_string(), &user.gender, &user.preference);
+            self.send_event(conn, &ServerMessage::WaitingForMatch {});
+        }
+    }
+
+    // Retry users whose shared-interest wait has expired, so they can now be matched randomly
+    async fn rematch_waiting(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<ConnId> = self
+            .waiting_users
+            .iter()
+            .filter(|id| {
+                self.users.get(*id).is_some_and(|user| {
+                    !user.interests.is_empty() && self.open_to_random(user, now)
+                })
+            })
+            .cloned()
+            .collect();
+
+        for conn in expired {
+            // The user may have been matched earlier in this sweep
+            let still_waiting = self.users.get(&conn).is_some_and(|user| user.partner_id.is_none());
+            if still_waiting {
+                if let Some((partner_id, shared)) = self.best_match(&conn) {
+                    self.connect_users(&conn, &partner_id, shared).await;
+                }
+            }
+        }
+    }
+
+    async fn connect_users(&mut self, user1_id: &ConnId, user2_id: &ConnId, shared_interests: Vec<String>) {
+        let now = Instant::now();
+        for user_id in [user1_id, user2_id] {
+            if let Some(since) = self.users.get(user_id).and_then(|user| user.waiting_since) {
+                self.metrics.match_wait.observe(now.duration_since(since).as_secs_f64());
+            }
+        }
+        let identity = |user: &User| (user.user_id.clone(), user.username.clone());
+        let user1_identity = self.users.get(user1_id).map(identity);
+        let user2_identity = self.users.get(user2_id).map(identity);
+        if let Some(user1) = self.users.get_mut(user1_id) {
+            user1.partner_id = Some(user2_id.to_string());
+            user1.waiting_since = None;
+            user1.last_partner = user2_identity;
+        }
+        if let Some(user2) = self.users.get_mut(user2_id) {
+            user2.partner_id = Some(user1_id.to_string());
+            user2.waiting_since = None;
+            user2.last_partner = user1_identity;
+        }
+        self.waiting_users.remove(user1_id);
+        self.waiting_users.remove(user2_id);
+        let event = ServerMessage::ChatStarted {
+            group_code: None,
+            shared_interests: Some(shared_interests),
+            ice_servers: self.ice_servers(),
+        };
+        self.send_event(user1_id, &event);
+        self.send_event(user2_id, &event);
+    }
+
+    async fn create_new_group(&mut self, conn: &ConnId, settings: GroupSettings) {
+        let GroupSettings { max_size, visibility, password, title, language } = settings;
+        let group_code = self.generate_group_code();
+        let ice_servers = self.ice_servers();
+        if let Some(user) = self.users.get_mut(conn) {
+            let group = Group {
+                code: group_code.clone(),
+                members: vec![conn.to_string()],
+                usernames: vec![user.username.clone()],
+                max_size,
+                visibility,
+                password,
+                title,
+                language,
+                owner: conn.clone(),
+                moderators: Vec::new(),
+                muted: HashMap::new(),
+                kicked: HashSet::new(),
+                keys: HashMap::new(),
+                fingerprints: HashMap::new(),
+            };
+            self.groups.insert(group_code.clone(), group);
+            user.group_id = Some(group_code.clone());
+            let username = user.username.clone();
+            self.send_event(conn, &ServerMessage::ChatStarted {
+                group_code: Some(group_code.clone()),
+                shared_interests: None,
+                ice_servers,
+            });
+            self.send_event(conn, &ServerMessage::GroupMembersUpdate(vec![username]));
+            if let Some(roles) = self.group_roles(&group_code) {
+                self.send_event(conn, &roles);
+            }
+        }
+    }
+
+    async fn join_group_by_code(&mut self, conn: &ConnId, group_code: &str, password: Option<&str>) {
+        let ice_servers = self.ice_servers();
+        let user_id = self.users.get(conn).map(|user| user.user_id.clone()).unwrap_or_default();
+        if let Some(group) = self.groups.get_mut(group_code) {
+            if group.kicked.contains(&user_id) {
+                self.send_event(conn, &ServerMessage::KickedFromGroup {});
+                return;
+            }
+            if let Some(expected) = &group.password {
+                if !password.is_some_and(|password| constant_time_eq(password.as_bytes(), expected.as_bytes())) {
+                    self.send_event(conn, &ServerMessage::GroupPasswordRequired {});
+                    return;
+                }
+            }
+            if group.members.len() >= group.max_size {
+                self.send_event(conn, &ServerMessage::GroupFull {});
+                return;
+            }
+            if let Some(user) = self.users.get_mut(conn) {
+                group.members.push(conn.to_string());
+                group.usernames.push(user.username.clone());
+                user.group_id = Some(group_code.to_string());
+                let username = user.username.clone();
+                let usernames = group.usernames.clone();
+                let key_bundle: Vec<MemberKey> = group.keys.values().cloned().collect();
+                let group_id = group_code.to_string();
+                self.broadcast_group(&group_id, None, &ServerMessage::GroupMembersUpdate(usernames));
+                self.broadcast_group(&group_id, Some(conn), &ServerMessage::UserJoinedGroup(username));
+                self.send_event(conn, &ServerMessage::ChatStarted {
+                    group_code: Some(group_id.clone()),
+                    shared_interests: None,
+                    ice_servers,
+                });
+                self.send_event(conn, &ServerMessage::GroupKeyBundle(key_bundle));
+                if let Some(roles) = self.group_roles(&group_id) {
+                    self.send_event(conn, &roles);
+                }
+            }
+        } else {
+            self.send_event(conn, &ServerMessage::GroupNotFound {});
+        }
+    }
+
+    // Join the public group with free slots that has the fewest members, so that random joiners
+    // spread out instead of piling into one group; create a public group if every one is full
+    async fn join_random_group(&mut self, conn: &ConnId) {
+        let user_id = self.users.get(conn).map(|user| user.user_id.clone()).unwrap_or_default();
+        let group_code_option = {
+            let open_groups = self.groups.values().filter(|g| {
+                g.visibility == GroupVisibility::Public
+                    && !g.members.is_empty()
+                    && g.members.len() < g.max_size
+                    && !g.kicked.contains(&user_id)
+            });
+            let fewest = open_groups.clone().map(|g| g.members.len()).min();
+            let smallest: Vec<&Group> = open_groups.filter(|g| Some(g.members.len()) == fewest).collect();
+            if smallest.is_empty() {
+                None
+            } else {
+                let random_index = rand::random::<usize>() % smallest.len();
+                Some(smallest[random_index].code.clone())
+            }
+        };
+        
+        match group_code_option {
+            Some(code) => self.join_group_by_code(conn, &code, None).await,
+            None => {
+                let settings = GroupSettings {
+                    max_size: self.config.max_group_size,
+                    visibility: GroupVisibility::Public,
+                    password: None,
+                    title: None,
+                    language: None,
+                };
+                self.create_new_group(conn, settings).await
+            }
+        }
+    }
+
+    // Validate the group options of a `join_chat`; they only take effect for `create`
+    fn group_settings(&self, profile: &UserProfile) -> Result<GroupSettings, RequestError> {
+        let max_size = profile.group_max_size.unwrap_or(self.config.max_group_size);
+        if !(MIN_GROUP_SIZE..=self.config.max_group_size).contains(&max_size) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("group_max_size must be between {} and {}", MIN_GROUP_SIZE, self.config.max_group_size),
+            ));
+        }
+        if profile.group_password.as_ref().is_some_and(|password| password.len() > MAX_GROUP_PASSWORD_LEN) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("group_password must be at most {} bytes", MAX_GROUP_PASSWORD_LEN),
+            ));
+        }
+        let visibility = profile.group_visibility.unwrap_or(GroupVisibility::Unlisted);
+        let password = match visibility {
+            GroupVisibility::Password => {
+                let password = profile.group_password.clone().filter(|password| !password.is_empty());
+                Some(password.ok_or_else(|| {
+                    RequestError::new(ErrorCode::InvalidPayload, "group_password is required for password groups")
+                })?)
+            }
+            GroupVisibility::Public | GroupVisibility::Unlisted => None,
+        };
+        let title = profile.group_title.as_deref().map(str::trim).filter(|title| !title.is_empty());
+        if title.is_some_and(|title| title.chars().count() > MAX_GROUP_TITLE_LEN) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("group_title must be at most {} characters", MAX_GROUP_TITLE_LEN),
+            ));
+        }
+        let language = normalize_language(profile.group_language.as_deref());
+        if language.as_ref().is_some_and(|language| language.len() > MAX_GROUP_LANGUAGE_LEN) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("group_language must be at most {} bytes", MAX_GROUP_LANGUAGE_LEN),
+            ));
+        }
+        Ok(GroupSettings {
+            max_size,
+            visibility,
+            password,
+            title: title.map(str::to_string),
+            language,
+        })
+    }
+
+    async fn handle_join(&mut self, conn: &ConnId, profile: UserProfile) -> Result<(), RequestError> {
+        if self.shutting_down {
+            return Err(RequestError::new(ErrorCode::InvalidState, "server is shutting down"));
+        }
+        // Creation options are only checked when a group is being created
+        let group_settings = match (profile.room_type.as_str(), profile.group_join_method.as_deref()) {
+            ("group", Some("create")) => Some(self.group_settings(&profile)?),
+            _ => None,
+        };
+        if profile.device_fingerprint.as_ref().is_some_and(|device| device.len() > MAX_DEVICE_FINGERPRINT_LEN) {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPayload,
+                format!("device_fingerprint must be at most {} bytes", MAX_DEVICE_FINGERPRINT_LEN),
+            ));
+        }
+        let client = self.sessions.get(conn).map(|session| session.client.clone()).unwrap_or_default();
+        let device_fingerprint = profile.device_fingerprint.clone().or(client.device_fingerprint);
+        if let Some(ban) = self.bans.find(Some(&profile.user_id), client.ip, device_fingerprint.as_deref()) {
+            log::info!("Rejected join from banned client {} (ban {})", conn, ban.id);
+            self.close_banned(conn, &ban).await;
+            return Ok(());
+        }
+        let user = User {
+            id: conn.clone(),
+            user_id: profile.user_id.clone(),
+            username: if profile.username.is_empty() { default_username(&profile.user_id) } else { profile.username.clone() },
+            gender: profile.gender.trim().to_lowercase(),
+            preference: profile.preference.trim().to_lowercase(),
+            room_type: profile.room_type.clone(),
+            partner_id: None,
+            group_id: None,
+            interests: normalize_interests(&profile.interests),
+            waiting_since: None,
+            last_partner: None,
+            device_fingerprint,
+        };
+        // A repeated join_chat leaves the previous chat first, so that no group keeps a ghost
+        // member or owner and no partner keeps pointing at this connection
+        self.handle_disconnect(conn).await;
+        self.users.insert(conn.clone(), user);
+        if profile.room_type == "group" {
+            match (group_settings, profile.group_join_method.as_deref(), &profile.group_code) {
+                (Some(settings), _, _) => self.create_new_group(conn, settings).await,
+                // Malformed codes and failed checksums are treated like codes of groups that do not exist
+                (None, Some("join"), Some(code)) => match self.config.group_code.parse(code) {
+                    Some(code) => self.join_group_by_code(conn, &code, profile.group_password.as_deref()).await,
+                    None => {
+                        self.send_event(conn, &ServerMessage::GroupNotFound {});
+                    }
+                },
+                _ => self.join_random_group(conn).await,
+            }
+        } else {
+            self.find_match(conn).await;
+        }
+        Ok(())
+    }
+
+    async fn run(&mut self, cmd_rx: &mut mpsc::Receiver<Queued>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sweep = interval(MATCH_SWEEP_INTERVAL);
+        loop {
+            let drain_deadline = self.drain_deadline.map(tokio::time::Instant::from_std);
+            let cmd = tokio::select! {
+                queued = cmd_rx.recv() => match queued {
+                    Some(Queued { command, queued_at }) => {
+                        self.metrics.command_latency.observe(queued_at.elapsed().as_secs_f64());
+                        command
+                    }
+                    None => break,
+                },
+                _ = sweep.tick() => {
+                    self.expire_detached_sessions().await;
+                    self.expire_recent_partners();
+                    self.rematch_waiting().await;
+                    self.evict_slow_consumers().await;
+                    continue;
+                }
+                _ = sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                    self.finish_shutdown();
+                    continue;
+                }
+            };
+            match cmd {
+                Command::Connect { conn_tx, close_tx, client, res_tx } => {
+                    let conn_id = Uuid::new_v4().to_string();
+                    let resume_token = Uuid::new_v4().to_string();
+                    self.resume_tokens.insert(resume_token.clone(), conn_id.clone());
+                    self.sessions.insert(conn_id.clone(), Session {
+                        tx: conn_tx,
+                        close_tx: Some(close_tx),
+                        socket_id: conn_id.clone(),
+                        resume_token: resume_token.clone(),
+                        client,
+                        connected_at: Instant::now(),
+                    });
+                    self.send_event(&conn_id, &ServerMessage::Connected {
+                        resume_token,
+                        resume_grace_ms: duration_ms(self.config.resume_grace_period),
+                    });
+                    let _ = res_tx.send(conn_id);
+                }
+                Command::Resume { conn, resume_token, res_tx } => {
+                    let result = self.handle_resume(&conn, &resume_token).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::Metrics { res_tx } => {
+                    let _ = res_tx.send(self.render_metrics(cmd_rx));
+                }
+                Command::Ping { res_tx } => {
+                    let _ = res_tx.send(());
+                }
+                #[cfg(test)]
+                Command::Panic => panic!("panic requested by a test"),
+                Command::Shutdown { res_tx } => {
+                    self.shutdown_waiters.push(res_tx);
+                    self.begin_shutdown();
+                    if self.sessions.is_empty() || self.drain_deadline.is_none() {
+                        self.finish_shutdown();
+                    }
+                }
+                Command::Disconnect { conn, socket, cause } => {
+                    *self.metrics.disconnects.entry(cause.label()).or_insert(0) += 1;
+                    // Ignore sockets whose session has since been resumed elsewhere
+                    if self.sessions.get(&conn).is_some_and(|session| session.socket_id == socket) {
+                        self.handle_socket_closed(&conn).await;
+                    }
+                }
+                Command::JoinChat { conn, profile, res_tx } => {
+                    let result = self.handle_join(&conn, profile).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::SendMessage { conn, message, is_group_chat, group_code, message_id, res_tx } => {
+                    let result = self.handle_send_message(&conn, message, is_group_chat, group_code, message_id);
+                    self.count_relayed("send_message", &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::MessageDelivered { conn, message_id, res_tx } => {
+                    let result = self.handle_message_delivered(&conn, message_id);
+                    self.count_relayed("message_delivered", &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::KeyExchange { conn, data, res_tx } => {
+                    let result = self.handle_key_exchange(&conn, data);
+                    self.count_relayed("key_exchange", &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::Signal { conn, route, signal, res_tx } => {
+                    let event = signal.event_name();
+                    let result = self.handle_signal(&conn, route, signal);
+                    self.count_relayed(event, &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::ReportUser { conn, data, res_tx } => {
+                    let result = self.handle_report(&conn, data);
+                    let _ = res_tx.send(result);
+                }
+                Command::ListReports { status, offset, limit, res_tx } => {
+                    let _ = res_tx.send(self.reports.list(status, offset, limit));
+                }
+                Command::GetReport { id, res_tx } => {
+                    let _ = res_tx.send(self.reports.get(id));
+                }
+                Command::ResolveReport { id, status, note, res_tx } => {
+                    let _ = res_tx.send(self.reports.resolve(id, status, note));
+                }
+                Command::CheckBan { client, res_tx } => {
+                    let ban = self.bans.find(None, client.ip, client.device_fingerprint.as_deref());
+                    let _ = res_tx.send(ban);
+                }
+                Command::ListBans { res_tx } => {
+                    let _ = res_tx.send(self.bans.list());
+                }
+                Command::AddBan { target, duration, reason, res_tx } => {
+                    let ban = self.handle_add_ban(target, duration, reason).await;
+                    let _ = res_tx.send(ban);
+                }
+                Command::RemoveBan { id, res_tx } => {
+                    let _ = res_tx.send(self.bans.remove(id));
+                }
+                Command::ListSessions { offset, limit, res_tx } => {
+                    let _ = res_tx.send(self.list_sessions(offset, limit));
+                }
+                Command::ListWaiting { res_tx } => {
+                    let _ = res_tx.send(self.list_waiting());
+                }
+                Command::ListPairs { offset, limit, res_tx } => {
+                    let _ = res_tx.send(self.list_pairs(offset, limit));
+                }
+                Command::ListGroups { offset, limit, res_tx } => {
+                    let _ = res_tx.send(self.list_groups(offset, limit));
+                }
+                Command::Kick { conn, res_tx } => {
+                    let _ = res_tx.send(self.kick(&conn).await);
+                }
+                Command::DissolveGroup { code, res_tx } => {
+                    let _ = res_tx.send(self.dissolve_group(&code));
+                }
+                Command::TypingStart { conn, is_group_chat, group_code, res_tx } => {
+                    let result = self
+                        .joined_user(&conn)
+                        .and_then(|user| {
+                            if is_group_chat {
+                                self.check_not_muted(user)?;
+                            }
+                            Ok(ServerMessage::TypingStarted {
+                                username: is_group_chat.then(|| user.username.clone()),
+                            })
+                        })
+                        .and_then(|event| self.relay_from(&conn, is_group_chat, group_code, &event).map(|_| ()));
+                    self.count_relayed("typing_start", &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::TypingStop { conn, is_group_chat, group_code, res_tx } => {
+                    let result = self
+                        .joined_user(&conn)
+                        .map(|user| ServerMessage::TypingStopped {
+                            username: is_group_chat.then(|| user.username.clone()),
+                        })
+                        .and_then(|event| self.relay_from(&conn, is_group_chat, group_code, &event).map(|_| ()));
+                    self.count_relayed("typing_stop", &result);
+                    let _ = res_tx.send(result);
+                }
+                Command::DisconnectChat { conn, res_tx } => {
+                    self.handle_disconnect(&conn).await;
+                    let _ = res_tx.send(());
+                }
+                Command::Next { conn, res_tx } => {
+                    let result = self.handle_next(&conn).await;
+                    let _ = res_tx.send(result);
+                }
+                Command::KickMember { conn, target, res_tx } => {
+                    let _ = res_tx.send(self.handle_kick_member(&conn, &target));
+                }
+                Command::MuteMember { conn, data, res_tx } => {
+                    let _ = res_tx.send(self.handle_mute_member(&conn, data));
+                }
+                Command::PromoteMember { conn, target, res_tx } => {
+                    let _ = res_tx.send(self.handle_promote_member(&conn, &target));
+                }
+                Command::BrowseGroups { conn, query, res_tx } => {
+                    self.send_event(&conn, &ServerMessage::GroupList(self.group_directory(&query)));
+                    let _ = res_tx.send(());
+                }
+                Command::GroupDirectory { query, res_tx } => {
+                    let _ = res_tx.send(self.group_directory(&query));
+                }
+            }
+            self.evict_slow_consumers().await;
+        }
+        Ok(())
+    }
+}
+
+pub fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+// Client-chosen message ids are echoed to other users, so they are kept short
+fn validate_message_id(message_id: &str) -> Result<(), RequestError> {
+    if message_id.is_empty() || message_id.len() > MAX_MESSAGE_ID_LEN {
+        return Err(RequestError::new(
+            ErrorCode::InvalidPayload,
+            format!("message_id must be 1 to {} bytes", MAX_MESSAGE_ID_LEN),
+        ));
+    }
+    Ok(())
+}
+
+// The key of two users in `recent_partners`, the same whichever of them comes first
+fn partner_pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+// Compare without returning early, so response timing does not reveal secrets
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Trim, lowercase and deduplicate interest tags, dropping empty or oversized ones
+fn normalize_interests(interests: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    interests
+        .iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty() && tag.chars().count() <= MAX_INTEREST_LEN)
+        .filter(|tag| seen.insert(tag.clone()))
+        .take(MAX_INTERESTS)
+        .collect()
+}
+
+// Trim and lowercase a language tag, treating an empty one as absent
+fn normalize_language(language: Option<&str>) -> Option<String> {
+    language.map(|language| language.trim().to_lowercase()).filter(|language| !language.is_empty())
+}
+
+// Name shown for users who did not pick one, from the first characters of their user id
+fn default_username(user_id: &str) -> String {
+    format!("User-{}", user_id.chars().take(5).collect::<String>())
+}
+
+// Serialize an event for the wire. Serializing these types cannot fail in practice, but a
+// failure should drop the event rather than take down the chat server.
+fn encode_event(event: &ServerMessage) -> Option<Msg> {
+    match serde_json::to_string(event) {
+        Ok(msg) => Some(msg),
+        Err(err) => {
+            log::error!("Failed to serialize event: {}", err);
+            None
+        }
+    }
+}
+
+// Interests present in both lists, in the order of the first list
+fn shared_interests(a: &[String], b: &[String]) -> Vec<String> {
+    a.iter().filter(|tag| b.contains(tag)).cloned().collect()
+}
+
+// Handle and command sender for chat server
+#[derive(Debug, Clone)]
+pub struct ChatServerHandle {
+    cmd_tx: CommandSender,
+    outbound_capacity: usize,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl ChatServerHandle {
+    // Send a command and wait for the chat server's answer
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T, ChatServerError> {
         let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
+        self.cmd_tx.send(command(res_tx)).await.map_err(|_| ChatServerError::Stopped)?;
+        res_rx.await.map_err(|_| ChatServerError::NoResponse)
+    }
+
+    // Register a new connection and obtain its ID and outbound channels
+    pub async fn connect(&self, client: ClientInfo) -> Result<Connection, ChatServerError> {
+        let (conn_tx, rx) = mpsc::channel(self.outbound_capacity);
+        let (close_tx, close_rx) = oneshot::channel();
+        let id = self.request(|res_tx| Command::Connect { conn_tx, close_tx, client, res_tx }).await?;
+        Ok(Connection { id, rx, close_rx })
+    }
+
+    // Unregister the socket; the user is kept for the resume grace period
+    pub async fn disconnect(&self, conn: ConnId, socket: ConnId, cause: CloseCause) -> Result<(), ChatServerError> {
         self.cmd_tx
-            .send(Command::TypingStart { conn, is_group_chat, group_code, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+            .send(Command::Disconnect { conn, socket, cause })
+            .await
+            .map_err(|_| ChatServerError::Stopped)
+    }
+
+    // Reclaim a dropped session, returning the connection ID this socket now acts as
+    pub async fn resume(&self, conn: ConnId, resume_token: String) -> Result<Result<ConnId, RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::Resume { conn, resume_token, res_tx }).await
+    }
+
+    // Round-trip a command through the chat server, reporting whether it answered in time
+    pub async fn ping(&self, timeout: Duration) -> bool {
+        let round_trip = self.request(|res_tx| Command::Ping { res_tx });
+        matches!(tokio::time::timeout(timeout, round_trip).await, Ok(Ok(())))
+    }
+
+    // Whether shutdown has begun and new connections should be refused
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    // Warn every client, wait for the drain timeout, then close every connection
+    pub async fn shutdown(&self) -> Result<(), ChatServerError> {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.request(|res_tx| Command::Shutdown { res_tx }).await
+    }
+
+    // Render the server's metrics in the Prometheus text format
+    pub async fn metrics(&self) -> Result<String, ChatServerError> {
+        self.request(|res_tx| Command::Metrics { res_tx }).await
+    }
+
+    // Join chat with a user profile
+    pub async fn join_chat(&self, conn: ConnId, profile: UserProfile) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::JoinChat { conn, profile, res_tx }).await
+    }
+
+    // Send a message
+    pub async fn send_message(&self, conn: ConnId, data: SendMessageData) -> Result<Result<(), RequestError>, ChatServerError> {
+        let SendMessageData { message, is_group_chat, group_code, message_id } = data;
+        self.request(|res_tx| Command::SendMessage { conn, message, is_group_chat, group_code, message_id, res_tx })
+            .await
+    }
+
+    // Publish a public key to the partner or group
+    pub async fn key_exchange(&self, conn: ConnId, data: KeyExchangeData) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::KeyExchange { conn, data, res_tx }).await
+    }
+
+    // Relay a WebRTC offer, answer or ICE candidate
+    pub async fn signal(&self, conn: ConnId, route: SignalRoute, signal: Signal) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::Signal { conn, route, signal, res_tx }).await
+    }
+
+    // Report the partner or a group member
+    pub async fn report_user(&self, conn: ConnId, data: ReportUserData) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::ReportUser { conn, data, res_tx }).await
+    }
+
+    // Reports in the moderation queue, oldest first
+    pub async fn list_reports(&self, status: Option<ReportStatus>, offset: usize, limit: usize) -> Result<Vec<Report>, ChatServerError> {
+        self.request(|res_tx| Command::ListReports { status, offset, limit, res_tx }).await
+    }
+
+    pub async fn get_report(&self, id: u64) -> Result<Option<Report>, ChatServerError> {
+        self.request(|res_tx| Command::GetReport { id, res_tx }).await
+    }
+
+    // Close a report, returning it unless it does not exist
+    pub async fn resolve_report(&self, id: u64, status: ReportStatus, note: Option<String>) -> Result<Option<Report>, ChatServerError> {
+        self.request(|res_tx| Command::ResolveReport { id, status, note, res_tx }).await
+    }
+
+    // The active ban covering a connecting client's IP address or device, if any
+    pub async fn check_ban(&self, client: ClientInfo) -> Result<Option<Ban>, ChatServerError> {
+        self.request(|res_tx| Command::CheckBan { client, res_tx }).await
+    }
+
+    pub async fn list_bans(&self) -> Result<Vec<Ban>, ChatServerError> {
+        self.request(|res_tx| Command::ListBans { res_tx }).await
+    }
+
+    // Ban a user id, IP address or device, disconnecting matching clients
+    pub async fn add_ban(&self, target: BanTarget, duration: Option<Duration>, reason: Option<String>) -> Result<Ban, ChatServerError> {
+        self.request(|res_tx| Command::AddBan { target, duration, reason, res_tx }).await
+    }
+
+    // Lift a ban, returning it unless it does not exist
+    pub async fn remove_ban(&self, id: u64) -> Result<Option<Ban>, ChatServerError> {
+        self.request(|res_tx| Command::RemoveBan { id, res_tx }).await
+    }
+
+    // Connected and detached sessions, for the admin API
+    pub async fn list_sessions(&self, offset: usize, limit: usize) -> Result<Vec<SessionInfo>, ChatServerError> {
+        self.request(|res_tx| Command::ListSessions { offset, limit, res_tx }).await
+    }
+
+    // Users waiting for a 1:1 partner, by gender and preference
+    pub async fn list_waiting(&self) -> Result<Vec<WaitingBucket>, ChatServerError> {
+        self.request(|res_tx| Command::ListWaiting { res_tx }).await
+    }
+
+    pub async fn list_pairs(&self, offset: usize, limit: usize) -> Result<Vec<PairInfo>, ChatServerError> {
+        self.request(|res_tx| Command::ListPairs { offset, limit, res_tx }).await
+    }
+
+    pub async fn list_groups(&self, offset: usize, limit: usize) -> Result<Vec<GroupInfo>, ChatServerError> {
+        self.request(|res_tx| Command::ListGroups { offset, limit, res_tx }).await
+    }
+
+    // Close a connection, returning whether it existed
+    pub async fn kick(&self, conn: ConnId) -> Result<bool, ChatServerError> {
+        self.request(|res_tx| Command::Kick { conn, res_tx }).await
+    }
+
+    // Remove a group, returning whether it existed
+    pub async fn dissolve_group(&self, code: RoomId) -> Result<bool, ChatServerError> {
+        self.request(|res_tx| Command::DissolveGroup { code, res_tx }).await
+    }
+
+    // Pass a delivery receipt to the sender of a message
+    pub async fn message_delivered(&self, conn: ConnId, message_id: String) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::MessageDelivered { conn, message_id, res_tx }).await
+    }
+
+    // Start typing
+    pub async fn typing_start(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::TypingStart { conn, is_group_chat, group_code, res_tx }).await
     }
 
     // Stop typing
-    pub async fn typing_stop(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::TypingStop { conn, is_group_chat, group_code, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+    pub async fn typing_stop(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::TypingStop { conn, is_group_chat, group_code, res_tx }).await
     }
 
     // Disconnect from chat
-    pub async fn disconnect_chat(&self, conn: ConnId) {
-        let (res_tx, res_rx) = oneshot::channel();
-        // unwrap: chat server should not have been dropped
-        self.cmd_tx
-            .send(Command::DisconnectChat { conn, res_tx })
-            .unwrap();
-        // unwrap: chat server does not drop our response channel
-        res_rx.await.unwrap();
+    pub async fn disconnect_chat(&self, conn: ConnId) -> Result<(), ChatServerError> {
+        self.request(|res_tx| Command::DisconnectChat { conn, res_tx }).await
+    }
+
+    // Skip the current partner and look for a new one
+    pub async fn next(&self, conn: ConnId) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::Next { conn, res_tx }).await
+    }
+
+    // Remove a member from the sender's group
+    pub async fn kick_member(&self, conn: ConnId, target: String) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::KickMember { conn, target, res_tx }).await
+    }
+
+    // Mute a member of the sender's group for a while
+    pub async fn mute_member(&self, conn: ConnId, data: MuteMemberData) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::MuteMember { conn, data, res_tx }).await
+    }
+
+    // Make a member of the sender's group a moderator
+    pub async fn promote_member(&self, conn: ConnId, target: String) -> Result<Result<(), RequestError>, ChatServerError> {
+        self.request(|res_tx| Command::PromoteMember { conn, target, res_tx }).await
+    }
+
+    // Send a page of the public group directory to a connection
+    pub async fn browse_groups(&self, conn: ConnId, query: ListGroupsData) -> Result<(), ChatServerError> {
+        self.request(|res_tx| Command::BrowseGroups { conn, query, res_tx }).await
+    }
+
+    // A page of the public group directory
+    pub async fn group_directory(&self, query: ListGroupsData) -> Result<GroupDirectoryPage, ChatServerError> {
+        self.request(|res_tx| Command::GroupDirectory { query, res_tx }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::protocol::ReportReason;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct TestClient {
+        conn: Connection,
+    }
+
+    impl TestClient {
+        async fn connect(server: &ChatServerHandle) -> Self {
+            let conn = server.connect(ClientInfo::default()).await.expect("server is running");
+            Self { conn }
+        }
+
+        fn id(&self) -> ConnId {
+            self.conn.id.clone()
+        }
+
+        // Events queued for this client so far. The server handles commands in order, so a
+        // ping round trip makes sure everything sent before it has been delivered.
+        async fn events(&mut self, server: &ChatServerHandle) -> Vec<Value> {
+            assert!(server.ping(TIMEOUT).await, "server stopped answering");
+            let mut events = Vec::new();
+            while let Ok(msg) = self.conn.rx.try_recv() {
+                events.push(serde_json::from_str(&msg).expect("server sends JSON"));
+            }
+            events
+        }
+    }
+
+    fn profile(fields: Value) -> UserProfile {
+        let mut profile = json!({
+            "user_id": "abcdefgh",
+            "username": "",
+            "preference": "any",
+            "gender": "male",
+            "room_type": "one",
+            "group_code": null,
+            "group_join_method": null,
+        });
+        for (key, value) in fields.as_object().expect("profile fields are an object") {
+            profile[key] = value.clone();
+        }
+        serde_json::from_value(profile).expect("valid profile")
+    }
+
+    fn group_profile(user_id: &str, username: &str, group_code: Option<&str>) -> UserProfile {
+        profile(json!({
+            "user_id": user_id,
+            "username": username,
+            "room_type": "group",
+            "group_code": group_code,
+            "group_join_method": if group_code.is_some() { "join" } else { "create" },
+        }))
+    }
+
+    fn find_event<'a>(events: &'a [Value], name: &str) -> Option<&'a Value> {
+        events.iter().find(|event| event["event"] == name).map(|event| &event["data"])
+    }
+
+    // The group code in a client's `chat_started`, if it got one
+    fn join_events_code(events: &[Value]) -> Option<String> {
+        find_event(events, "chat_started")?["groupCode"].as_str().map(str::to_owned)
+    }
+
+    // Create a group as the given client and return its code
+    async fn create_group(server: &ChatServerHandle, client: &mut TestClient, user_id: &str, username: &str) -> String {
+        server.join_chat(client.id(), group_profile(user_id, username, None)).await.unwrap().unwrap();
+        join_events_code(&client.events(server).await).expect("group creator gets chat_started with the code")
+    }
+
+    async fn join_group(server: &ChatServerHandle, client: &mut TestClient, user_id: &str, username: &str, code: &str) {
+        server.join_chat(client.id(), group_profile(user_id, username, Some(code))).await.unwrap().unwrap();
+        client.events(server).await;
+    }
+
+    #[tokio::test]
+    async fn owner_rejoining_hands_ownership_to_remaining_member() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let code = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        join_group(&server, &mut bob, "bbbbbbbb", "bob", &code).await;
+        alice.events(&server).await;
+
+        server.join_chat(alice.id(), group_profile("aaaaaaaa", "alice", None)).await.unwrap().unwrap();
+
+        let events = bob.events(&server).await;
+        assert_eq!(find_event(&events, "user_left_group"), Some(&json!("alice")));
+        assert_eq!(find_event(&events, "group_owner_changed"), Some(&json!({ "username": "bob" })));
+        let groups = server.list_groups(0, 10).await.unwrap();
+        let old_group = groups.iter().find(|group| group.code == code).expect("old group still has bob");
+        assert_eq!(old_group.usernames, vec!["bob".to_owned()]);
+        assert_eq!(old_group.owner, "bob");
+    }
+
+    #[tokio::test]
+    async fn creation_options_are_ignored_unless_creating_a_group() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let mut carol = TestClient::connect(&server).await;
+        let code = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        let bad_options = |mut fields: Value| {
+            fields["group_max_size"] = json!(0);
+            fields["group_title"] = json!("t".repeat(1000));
+            fields["group_visibility"] = json!("password");
+            profile(fields)
+        };
+
+        let join = bad_options(json!({ "user_id": "bbbbbbbb", "room_type": "group", "group_join_method": "join", "group_code": code }));
+        assert!(server.join_chat(bob.id(), join).await.unwrap().is_ok());
+        assert!(find_event(&bob.events(&server).await, "chat_started").is_some());
+
+        let one_to_one = bad_options(json!({ "user_id": "cccccccc" }));
+        assert!(server.join_chat(carol.id(), one_to_one).await.unwrap().is_ok());
+        assert!(find_event(&carol.events(&server).await, "waiting_for_match").is_some());
+
+        let create = bad_options(json!({ "user_id": "cccccccc", "room_type": "group", "group_join_method": "create" }));
+        assert!(server.join_chat(carol.id(), create).await.unwrap().is_err());
+    }
+
+    fn group_message(code: &str) -> SendMessageData {
+        SendMessageData {
+            message: EncryptedMessage {
+                encrypted: "ciphertext".to_owned(),
+                nonce: "nonce".to_owned(),
+            },
+            is_group_chat: true,
+            group_code: Some(code.to_owned()),
+            message_id: Some("m1".to_owned()),
+        }
+    }
+
+    // Try every group-scoped relay from `conn` into the group with `code`, expecting each to be refused
+    async fn assert_cannot_reach_group(server: &ChatServerHandle, conn: &ConnId, code: &str) {
+        let results = [
+            server.send_message(conn.clone(), group_message(code)).await.unwrap(),
+            server.typing_start(conn.clone(), true, Some(code.to_owned())).await.unwrap(),
+            server.typing_stop(conn.clone(), true, Some(code.to_owned())).await.unwrap(),
+        ];
+        for result in results {
+            let err = result.expect_err("relay into a foreign group must fail");
+            assert_eq!(err.code, ErrorCode::NotGroupMember);
+        }
+    }
+
+    #[tokio::test]
+    async fn non_members_cannot_relay_into_a_group() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let mut carol = TestClient::connect(&server).await;
+        let mut eve = TestClient::connect(&server).await;
+        create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        let target = create_group(&server, &mut bob, "bbbbbbbb", "bob").await;
+        join_group(&server, &mut carol, "cccccccc", "carol", &target).await;
+        server.join_chat(eve.id(), profile(json!({ "user_id": "eeeeeeee", "username": "eve" }))).await.unwrap().unwrap();
+        bob.events(&server).await;
+        eve.events(&server).await;
+
+        // A member of another group forging the code, and a 1:1 user who is in no group at all
+        assert_cannot_reach_group(&server, &alice.id(), &target).await;
+        assert_cannot_reach_group(&server, &eve.id(), &target).await;
+
+        assert_eq!(bob.events(&server).await, Vec::<Value>::new());
+        assert_eq!(carol.events(&server).await, Vec::<Value>::new());
+        assert!(find_event(&alice.events(&server).await, "message_ack").is_none());
+    }
+
+    // Empty, single-character, multibyte, combining, control and very long strings
+    fn odd_string(rng: &mut StdRng) -> String {
+        const CHARS: &[char] = &['a', 'Z', '0', ' ', '-', 'é', '漢', '😀', '\u{0301}', '\u{200b}', '\0', '\n'];
+        match rng.gen_range(0..6) {
+            0 => String::new(),
+            1 => CHARS.choose(rng).unwrap().to_string(),
+            2 => "😀".repeat(rng.gen_range(1..10)),
+            3 => "x".repeat(rng.gen_range(10_000..100_000)),
+            _ => (0..rng.gen_range(1..40)).map(|_| *CHARS.choose(rng).unwrap()).collect(),
+        }
+    }
+
+    fn odd_group_code(rng: &mut StdRng, known: &[String]) -> Value {
+        match (rng.gen_range(0..6), known.choose(rng)) {
+            (0, _) => Value::Null,
+            (1, Some(code)) => json!(code),
+            (2, Some(code)) => json!(format!("  {}\t", code.to_lowercase())),
+            (3, Some(code)) => json!(code.chars().rev().collect::<String>()),
+            _ => json!(odd_string(rng)),
+        }
+    }
+
+    fn random_join(rng: &mut StdRng, known_codes: &[String]) -> UserProfile {
+        let room_type = *["group", "one", "GROUP", "", "groups"].choose(rng).unwrap();
+        let method = [json!("create"), json!("join"), json!("random"), Value::Null, json!("JOIN"), json!("")]
+            .choose(rng)
+            .unwrap()
+            .clone();
+        let max_size = [Value::Null, json!(0), json!(1), json!(2), json!(20), json!(usize::MAX)].choose(rng).unwrap().clone();
+        let visibility = [Value::Null, json!("public"), json!("unlisted"), json!("password")].choose(rng).unwrap().clone();
+        let optional = |rng: &mut StdRng| if rng.gen_bool(0.3) { Value::Null } else { json!(odd_string(rng)) };
+        profile(json!({
+            "user_id": odd_string(rng),
+            "username": odd_string(rng),
+            "preference": odd_string(rng),
+            "gender": odd_string(rng),
+            "room_type": room_type,
+            "group_code": odd_group_code(rng, known_codes),
+            "group_join_method": method,
+            "group_max_size": max_size,
+            "group_visibility": visibility,
+            "group_password": optional(rng),
+            "group_title": optional(rng),
+            "group_language": optional(rng),
+            "interests": (0..rng.gen_range(0..15)).map(|_| odd_string(rng)).collect::<Vec<_>>(),
+            "device_fingerprint": optional(rng),
+        }))
+    }
+
+    #[tokio::test]
+    async fn arbitrary_join_payloads_keep_the_server_alive() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut clients = Vec::new();
+        for _ in 0..16 {
+            clients.push(TestClient::connect(&server).await);
+        }
+        let mut known_codes: Vec<String> = Vec::new();
+
+        for _ in 0..1000 {
+            let client = clients.choose_mut(&mut rng).unwrap();
+            let join = random_join(&mut rng, &known_codes);
+            // Many of these profiles are rejected; the server only has to keep answering
+            let _ = server.join_chat(client.id(), join).await.expect("server keeps answering");
+            for event in client.events(&server).await {
+                if let Some(code) = event["data"]["groupCode"].as_str() {
+                    known_codes.push(code.to_owned());
+                }
+            }
+        }
+
+        assert!(!known_codes.is_empty(), "some joins should have created groups");
+        assert!(server.ping(TIMEOUT).await);
+        let metrics = server.metrics().await.unwrap();
+        assert!(metrics.contains("chat_actor_restarts_total 0"), "chat server panicked:\n{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn bans_and_reports_survive_a_restart() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        server.join_chat(alice.id(), profile(json!({ "user_id": "aaaaaaaa" }))).await.unwrap().unwrap();
+        server.join_chat(bob.id(), profile(json!({ "user_id": "bbbbbbbb" }))).await.unwrap().unwrap();
+        let report = ReportUserData {
+            reason: ReportReason::Spam,
+            target: None,
+            details: None,
+            messages: Vec::new(),
+        };
+        server.report_user(alice.id(), report).await.unwrap().unwrap();
+        server.add_ban(BanTarget::UserId("bbbbbbbb".to_owned()), None, None).await.unwrap();
+        alice.events(&server).await;
+        bob.events(&server).await;
+
+        server.cmd_tx.send(Command::Panic).await.unwrap();
+
+        assert!(server.ping(TIMEOUT).await);
+        assert!(server.metrics().await.unwrap().contains("chat_actor_restarts_total 1"));
+        assert_eq!(server.list_bans().await.unwrap().len(), 1);
+        assert_eq!(server.list_reports(None, 0, 10).await.unwrap().len(), 1);
+        let mut banned = TestClient::connect(&server).await;
+        server.join_chat(banned.id(), profile(json!({ "user_id": "bbbbbbbb" }))).await.unwrap().unwrap();
+        assert!(find_event(&banned.events(&server).await, "banned").is_some());
+        assert_eq!(banned.conn.close_rx.await, Ok(DisconnectReason::Banned));
+    }
+
+    #[tokio::test]
+    async fn muted_member_stays_muted_after_rejoining() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let code = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        join_group(&server, &mut bob, "bbbbbbbb", "bob", &code).await;
+        let mute = MuteMemberData {
+            target: "bob".to_owned(),
+            duration_secs: 60,
+        };
+        server.mute_member(alice.id(), mute).await.unwrap().unwrap();
+
+        join_group(&server, &mut bob, "bbbbbbbb", "bob", &code).await;
+
+        let err = server.send_message(bob.id(), group_message(&code)).await.unwrap().unwrap_err();
+        assert_eq!(err.code, ErrorCode::Muted);
+        let err = server.typing_start(bob.id(), true, Some(code)).await.unwrap().unwrap_err();
+        assert_eq!(err.code, ErrorCode::Muted);
+    }
+
+    #[tokio::test]
+    async fn kicked_member_cannot_rejoin() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let code = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        join_group(&server, &mut bob, "bbbbbbbb", "bob", &code).await;
+        server.kick_member(alice.id(), "bob".to_owned()).await.unwrap().unwrap();
+        bob.events(&server).await;
+
+        server.join_chat(bob.id(), group_profile("bbbbbbbb", "bob", Some(&code))).await.unwrap().unwrap();
+
+        let events = bob.events(&server).await;
+        assert!(find_event(&events, "kicked_from_group").is_some());
+        assert!(find_event(&events, "chat_started").is_none());
+        let groups = server.list_groups(0, 10).await.unwrap();
+        assert_eq!(groups[0].usernames, vec!["alice".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn members_cannot_moderate_equal_or_higher_rank() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let mut carol = TestClient::connect(&server).await;
+        let code = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        join_group(&server, &mut bob, "bbbbbbbb", "bob", &code).await;
+        join_group(&server, &mut carol, "cccccccc", "carol", &code).await;
+        let mute = |target: &str| MuteMemberData {
+            target: target.to_owned(),
+            duration_secs: 60,
+        };
+        let assert_forbidden = |result: Result<(), RequestError>| {
+            assert_eq!(result.unwrap_err().code, ErrorCode::Forbidden);
+        };
+
+        // Plain members cannot moderate anyone
+        assert_forbidden(server.kick_member(bob.id(), "carol".to_owned()).await.unwrap());
+        assert_forbidden(server.mute_member(bob.id(), mute("carol")).await.unwrap());
+
+        // Moderators cannot moderate each other or the owner
+        server.promote_member(alice.id(), "bob".to_owned()).await.unwrap().unwrap();
+        server.promote_member(alice.id(), "carol".to_owned()).await.unwrap().unwrap();
+        assert_forbidden(server.kick_member(bob.id(), "carol".to_owned()).await.unwrap());
+        assert_forbidden(server.mute_member(bob.id(), mute("carol")).await.unwrap());
+        assert_forbidden(server.kick_member(bob.id(), "alice".to_owned()).await.unwrap());
+        assert_forbidden(server.mute_member(bob.id(), mute("alice")).await.unwrap());
+        assert_forbidden(server.promote_member(bob.id(), "alice".to_owned()).await.unwrap());
+
+        let groups = server.list_groups(0, 10).await.unwrap();
+        assert_eq!(groups[0].usernames.len(), 3);
+    }
+
+    fn one_to_one_profile(user_id: &str, interests: &[&str]) -> UserProfile {
+        profile(json!({ "user_id": user_id, "username": user_id, "interests": interests }))
+    }
+
+    // The sorted `sharedInterests` of the `chat_started` a client got, if it got one
+    fn shared_interests_of(events: &[Value]) -> Option<Vec<String>> {
+        let started = find_event(events, "chat_started")?;
+        let mut shared: Vec<String> = serde_json::from_value(started["sharedInterests"].clone()).unwrap();
+        shared.sort();
+        Some(shared)
+    }
+
+    #[tokio::test]
+    async fn partner_with_the_most_shared_interests_is_preferred() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut bob = TestClient::connect(&server).await;
+        let mut carol = TestClient::connect(&server).await;
+        let mut dan = TestClient::connect(&server).await;
+        server.join_chat(bob.id(), one_to_one_profile("bob", &["chess", "music"])).await.unwrap().unwrap();
+        server.join_chat(carol.id(), one_to_one_profile("carol", &["hiking"])).await.unwrap().unwrap();
+        assert_eq!(shared_interests_of(&bob.events(&server).await), None);
+
+        server.join_chat(dan.id(), one_to_one_profile("dan", &[" Music", "CHESS", "hiking"])).await.unwrap().unwrap();
+
+        let both = Some(vec!["chess".to_owned(), "music".to_owned()]);
+        assert_eq!(shared_interests_of(&dan.events(&server).await), both);
+        assert_eq!(shared_interests_of(&bob.events(&server).await), both);
+        assert_eq!(shared_interests_of(&carol.events(&server).await), None);
+    }
+
+    #[tokio::test]
+    async fn random_match_waits_for_the_interest_timeout_on_both_sides() {
+        let config = ChatServerConfig {
+            interest_match_timeout: Duration::from_millis(600),
+            ..ChatServerConfig::default()
+        };
+        let server = ChatServer::start(config);
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        server.join_chat(alice.id(), one_to_one_profile("alice", &["chess"])).await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(700)).await;
+
+        // Alice has waited long enough for anyone, but Bob has only just started waiting
+        server.join_chat(bob.id(), one_to_one_profile("bob", &["hiking"])).await.unwrap().unwrap();
+        assert_eq!(shared_interests_of(&alice.events(&server).await), None);
+        assert!(find_event(&bob.events(&server).await, "waiting_for_match").is_some());
+
+        // The next sweeps after Bob's timeout pair them with nothing in common
+        tokio::time::sleep(Duration::from_millis(600) + 2 * MATCH_SWEEP_INTERVAL).await;
+        assert_eq!(shared_interests_of(&alice.events(&server).await), Some(Vec::new()));
+        assert_eq!(shared_interests_of(&bob.events(&server).await), Some(Vec::new()));
+    }
+
+    fn direct_message(message_id: &str) -> SendMessageData {
+        SendMessageData {
+            message: EncryptedMessage {
+                encrypted: "ciphertext".to_owned(),
+                nonce: "nonce".to_owned(),
+            },
+            is_group_chat: false,
+            group_code: None,
+            message_id: Some(message_id.to_owned()),
+        }
+    }
+
+    // Two connected clients paired in a 1:1 chat, with their queues drained. Also returns the
+    // second client's resume token.
+    async fn paired_clients(server: &ChatServerHandle) -> (TestClient, TestClient, String) {
+        let mut alice = TestClient::connect(server).await;
+        let mut bob = TestClient::connect(server).await;
+        server.join_chat(alice.id(), profile(json!({ "user_id": "aaaaaaaa" }))).await.unwrap().unwrap();
+        server.join_chat(bob.id(), profile(json!({ "user_id": "bbbbbbbb" }))).await.unwrap().unwrap();
+        alice.events(server).await;
+        let events = bob.events(server).await;
+        assert!(find_event(&events, "chat_started").is_some());
+        let token = find_event(&events, "connected").unwrap()["resume_token"].as_str().unwrap().to_owned();
+        (alice, bob, token)
+    }
+
+    #[tokio::test]
+    async fn resumed_session_receives_messages_buffered_during_the_grace_period() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let (mut alice, bob, token) = paired_clients(&server).await;
+        server.disconnect(bob.id(), bob.id(), CloseCause::HeartbeatTimeout).await.unwrap();
+
+        server.send_message(alice.id(), direct_message("m1")).await.unwrap().unwrap();
+        let mut bob_again = TestClient::connect(&server).await;
+        let resumed = server.resume(bob_again.id(), token).await.unwrap().unwrap();
+
+        assert_eq!(resumed, bob.id());
+        let events = bob_again.events(&server).await;
+        let names: Vec<&str> = events.iter().filter_map(|event| event["event"].as_str()).collect();
+        assert_eq!(names, ["connected", "resumed", "receive_message"]);
+        assert_eq!(events[2]["data"]["message_id"], "m1");
+        let events = alice.events(&server).await;
+        assert_eq!(find_event(&events, "message_ack").unwrap()["status"], "delivered");
+        assert!(find_event(&events, "partner_disconnected").is_none());
+    }
+
+    #[tokio::test]
+    async fn disconnect_from_a_replaced_socket_is_ignored() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let (mut alice, bob, token) = paired_clients(&server).await;
+        let mut bob_again = TestClient::connect(&server).await;
+        let old_socket = bob.id();
+        server.resume(bob_again.id(), token).await.unwrap().unwrap();
+        bob_again.events(&server).await;
+
+        // The old socket's task only notices it was replaced after the resume went through
+        server.disconnect(bob.id(), old_socket, CloseCause::Server(DisconnectReason::Replaced)).await.unwrap();
+        server.send_message(alice.id(), direct_message("m2")).await.unwrap().unwrap();
+
+        let events = bob_again.events(&server).await;
+        assert_eq!(find_event(&events, "receive_message").unwrap()["message_id"], "m2");
+        assert!(find_event(&alice.events(&server).await, "partner_disconnected").is_none());
+        assert_eq!(server.list_sessions(0, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn adding_a_ban_closes_a_paired_user() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let (mut alice, mut bob, _) = paired_clients(&server).await;
+
+        let reason = Some("spam".to_owned());
+        server.add_ban(BanTarget::UserId("bbbbbbbb".to_owned()), Some(Duration::from_secs(3600)), reason).await.unwrap();
+
+        let events = bob.events(&server).await;
+        let banned = find_event(&events, "banned").expect("banned user is told why");
+        assert_eq!(banned["reason"], "spam");
+        assert!(banned["remaining_ms"].as_u64().is_some_and(|ms| ms > 0));
+        assert_eq!(bob.conn.close_rx.await, Ok(DisconnectReason::Banned));
+        assert!(find_event(&alice.events(&server).await, "partner_disconnected").is_some());
+        assert!(server.list_pairs(0, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn banned_user_id_is_refused_at_join() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        server.add_ban(BanTarget::UserId("bbbbbbbb".to_owned()), None, None).await.unwrap();
+        let mut bob = TestClient::connect(&server).await;
+
+        server.join_chat(bob.id(), profile(json!({ "user_id": "bbbbbbbb" }))).await.unwrap().unwrap();
+
+        let events = bob.events(&server).await;
+        assert!(find_event(&events, "banned").is_some());
+        assert!(find_event(&events, "waiting_for_match").is_none());
+        assert_eq!(bob.conn.close_rx.await, Ok(DisconnectReason::Banned));
+        assert!(server.list_waiting().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn password_group_needs_the_right_password() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let create = profile(json!({
+            "user_id": "aaaaaaaa",
+            "room_type": "group",
+            "group_join_method": "create",
+            "group_visibility": "password",
+            "group_password": "hunter2",
+        }));
+        server.join_chat(alice.id(), create).await.unwrap().unwrap();
+        let code = join_events_code(&alice.events(&server).await).unwrap();
+
+        for password in [Value::Null, json!(""), json!("hunter3")] {
+            let join = profile(json!({
+                "user_id": "bbbbbbbb",
+                "room_type": "group",
+                "group_join_method": "join",
+                "group_code": code,
+                "group_password": password,
+            }));
+            server.join_chat(bob.id(), join).await.unwrap().unwrap();
+            let events = bob.events(&server).await;
+            assert!(find_event(&events, "group_password_required").is_some(), "joined with {}", password);
+            assert!(find_event(&events, "chat_started").is_none());
+        }
+
+        let join = profile(json!({
+            "user_id": "bbbbbbbb",
+            "room_type": "group",
+            "group_join_method": "join",
+            "group_code": code,
+            "group_password": "hunter2",
+        }));
+        server.join_chat(bob.id(), join).await.unwrap().unwrap();
+        assert_eq!(join_events_code(&bob.events(&server).await), Some(code));
+    }
+
+    #[tokio::test]
+    async fn random_join_only_lands_in_public_groups() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let mut alice = TestClient::connect(&server).await;
+        let mut bob = TestClient::connect(&server).await;
+        let unlisted = create_group(&server, &mut alice, "aaaaaaaa", "alice").await;
+        let create = profile(json!({
+            "user_id": "bbbbbbbb",
+            "room_type": "group",
+            "group_join_method": "create",
+            "group_visibility": "password",
+            "group_password": "hunter2",
+        }));
+        server.join_chat(bob.id(), create).await.unwrap().unwrap();
+        let password = join_events_code(&bob.events(&server).await).unwrap();
+
+        let mut random_codes = Vec::new();
+        for user_id in ["cccccccc", "dddddddd", "eeeeeeee"] {
+            let mut client = TestClient::connect(&server).await;
+            let random = profile(json!({ "user_id": user_id, "room_type": "group", "group_join_method": "random" }));
+            server.join_chat(client.id(), random).await.unwrap().unwrap();
+            random_codes.push(join_events_code(&client.events(&server).await).unwrap());
+        }
+
+        // The first random joiner creates a public group, which the others then join
+        assert!(random_codes.iter().all(|code| code != &unlisted && code != &password));
+        assert!(random_codes.iter().all(|code| code == &random_codes[0]));
+        let groups = server.list_groups(0, 10).await.unwrap();
+        let random_group = groups.iter().find(|group| group.code == random_codes[0]).unwrap();
+        assert_eq!(random_group.visibility, GroupVisibility::Public);
+        assert_eq!(random_group.member_count, 3);
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn typing_is_dropped_before_a_full_queue_closes_the_connection() {
+        let config = ChatServerConfig {
+            outbound_queue_capacity: 8,
+            ..ChatServerConfig::default()
+        };
+        let server = ChatServer::start(config);
+        let (mut alice, mut bob, _) = paired_clients(&server).await;
+
+        // Bob stops reading. Once his queue is mostly full, typing indicators are dropped...
+        for i in 0..7 {
+            server.send_message(alice.id(), direct_message(&i.to_string())).await.unwrap().unwrap();
+            alice.events(&server).await;
+        }
+        server.typing_start(alice.id(), false, None).await.unwrap().unwrap();
+        server.send_message(alice.id(), direct_message("7")).await.unwrap().unwrap();
+        alice.events(&server).await;
+        // ...and a message that does not fit closes the connection
+        server.send_message(alice.id(), direct_message("8")).await.unwrap().unwrap();
+
+        assert_eq!(bob.conn.close_rx.await, Ok(DisconnectReason::SlowConsumer));
+        let mut received = Vec::new();
+        while let Ok(msg) = bob.conn.rx.try_recv() {
+            let event: Value = serde_json::from_str(&msg).unwrap();
+            received.push(event["data"]["message_id"].as_str().unwrap_or_default().to_owned());
+        }
+        assert_eq!(received, ["0", "1", "2", "3", "4", "5", "6", "7"]);
+        let metrics = server.metrics().await.unwrap();
+        assert!(metrics.contains("chat_typing_events_dropped_total 1"));
+        assert!(metrics.contains("chat_slow_consumer_disconnects_total 1"));
+        assert!(find_event(&alice.events(&server).await, "partner_disconnected").is_some());
+    }
+
+    #[tokio::test]
+    async fn delivery_receipts_need_a_valid_message_id() {
+        let server = ChatServer::start(ChatServerConfig::default());
+        let (mut alice, bob, _) = paired_clients(&server).await;
+
+        for message_id in [String::new(), "x".repeat(MAX_MESSAGE_ID_LEN + 1)] {
+            let err = server.message_delivered(bob.id(), message_id).await.unwrap().unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidPayload);
+        }
+        server.message_delivered(bob.id(), "x".repeat(MAX_MESSAGE_ID_LEN)).await.unwrap().unwrap();
+
+        let events = alice.events(&server).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(find_event(&events, "message_delivered").unwrap()["message_id"], "x".repeat(MAX_MESSAGE_ID_LEN));
+    }
+
+    #[test]
+    fn ice_candidate_media_id_is_bounded() {
+        let candidate = |sdp_mid: String| Signal::IceCandidate {
+            candidate: "candidate:1 1 udp 2122260223 192.0.2.1 54400 typ host".to_owned(),
+            sdp_mid: Some(sdp_mid),
+            sdp_m_line_index: Some(0),
+        };
+        assert!(candidate("0".to_owned()).validate().is_ok());
+        assert!(candidate("x".repeat(MAX_SDP_MID_LEN)).validate().is_ok());
+        let err = candidate("x".repeat(MAX_SDP_MID_LEN + 1)).validate().unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidPayload);
+    }
+}